@@ -0,0 +1,376 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::{command, service};
+use std::collections::BTreeMap;
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::thread;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Socket {
+	#[serde(alias="listen-stream", default="service::xvec")]
+	listen_stream: Vec<String>,
+	#[serde(alias="listen-datagram", default="service::xvec")]
+	listen_datagram: Vec<String>,
+	#[serde(alias="socket-mode", default="mode_rw", deserialize_with="service::mode")]
+	socket_mode: Option<libc::mode_t>,
+	#[serde(default="service::xfalse")]
+	pub accept: bool,
+	#[serde(alias="max-connections", default="max_connections")]
+	max_connections: usize,
+}
+
+fn mode_rw() -> Option<libc::mode_t> {
+	Some(0o666)
+}
+
+fn max_connections() -> usize {
+	64
+}
+
+/// A connection accepted by the supervisor, handled by its own instance of the service.
+pub struct Connection {
+	pub fd: OwnedFd,
+	pub remote: Option<SocketAddr>,
+}
+
+/// First file descriptor passed to the services, after stdin, stdout and stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets bound by the supervisor, they stay open while the services come and go.
+static SOCKETS: Mutex<BTreeMap<String, Vec<OwnedFd>>> = Mutex::new(BTreeMap::new());
+
+/// Parse "0.0.0.0:80", "[::1]:80" or a port listening on every IPv4 address.
+fn address(listen: &str) -> Option<SocketAddr> {
+	match listen.parse::<u16>() {
+		Ok(port) => Some(SocketAddr::from(([0, 0, 0, 0], port))),
+		Err(_) => listen.parse().ok(),
+	}
+}
+
+impl Socket {
+	/// Addresses the supervisor listens on, streams first.
+	fn listens(&self) -> impl Iterator<Item = (&String, bool)> {
+		let streams = self.listen_stream.iter().map(|listen| (listen, true));
+		streams.chain(self.listen_datagram.iter().map(|listen| (listen, false)))
+	}
+
+	/// Details shown by `control status --verbose`.
+	pub fn properties(&self) -> Vec<(&'static str, String)> {
+		let mut properties = vec![];
+
+		if ! self.listen_stream.is_empty() {
+			properties.push(("Listen stream", self.listen_stream.join(", ")));
+		}
+
+		if ! self.listen_datagram.is_empty() {
+			properties.push(("Listen datagram", self.listen_datagram.join(", ")));
+		}
+
+		if self.accept {
+			properties.push(("Accept", format!("up to {} connections", self.max_connections)));
+		}
+
+		properties
+	}
+
+	/// Problems that would prevent the sockets from being bound.
+	pub fn problems(&self) -> Vec<(&'static str, String)> {
+		let mut problems = vec![];
+
+		if self.listen_stream.is_empty() && self.listen_datagram.is_empty() {
+			problems.push(("socket", "socket without listen-stream or listen-datagram".into()));
+		}
+
+		if self.accept && ! self.listen_datagram.is_empty() {
+			problems.push(("socket.accept", "datagram sockets can't accept connections".into()));
+		}
+
+		if self.accept && self.max_connections == 0 {
+			problems.push(("socket.max-connections", "no connection would ever be accepted".into()));
+		}
+
+		for (listen, stream) in self.listens() {
+			let key = if stream { "socket.listen-stream" } else { "socket.listen-datagram" };
+			if ! listen.starts_with('/') && address(listen).is_none() {
+				problems.push((key, format!("invalid address `{listen}`")));
+			}
+		}
+
+		problems
+	}
+
+	fn bind(&self) -> io::Result<Vec<OwnedFd>> {
+		let mut fds = vec![];
+
+		for (listen, stream) in self.listens() {
+			let fd: OwnedFd = if listen.starts_with('/') {
+				// Sockets left by a previous supervisor are in the way.
+				if fs::symlink_metadata(listen).is_ok_and(|meta| meta.file_type().is_socket()) {
+					fs::remove_file(listen)?;
+				}
+
+				let fd: OwnedFd = match stream {
+					true => UnixListener::bind(listen)?.into(),
+					false => UnixDatagram::bind(listen)?.into(),
+				};
+
+				if let Some(mode) = self.socket_mode {
+					fs::set_permissions(listen, fs::Permissions::from_mode(mode))?;
+				}
+
+				fd
+			} else {
+				let address = address(listen).ok_or(io::ErrorKind::InvalidInput)?;
+				match stream {
+					true => TcpListener::bind(address)?.into(),
+					false => UdpSocket::bind(address)?.into(),
+				}
+			};
+
+			fds.push(fd);
+		}
+
+		Ok(fds)
+	}
+}
+
+/// The sockets of the service, bound on first use.
+pub fn sockets(service_name: &str, socket: &Socket) -> io::Result<Vec<RawFd>> {
+	let mut sockets = SOCKETS.lock().unwrap();
+
+	if ! sockets.contains_key(service_name) {
+		sockets.insert(service_name.into(), socket.bind()?);
+	}
+
+	Ok(sockets[service_name].iter().map(|fd| fd.as_raw_fd()).collect())
+}
+
+/// Address of the peer, for TCP connections.
+unsafe fn remote(addr: &libc::sockaddr_storage) -> Option<SocketAddr> {
+	match addr.ss_family as libc::c_int {
+		libc::AF_INET => {
+			let addr = &*(addr as *const _ as *const libc::sockaddr_in);
+			let ip = addr.sin_addr.s_addr.to_ne_bytes();
+			Some(SocketAddr::from((ip, u16::from_be(addr.sin_port))))
+		},
+		libc::AF_INET6 => {
+			let addr = &*(addr as *const _ as *const libc::sockaddr_in6);
+			Some(SocketAddr::from((addr.sin6_addr.s6_addr, u16::from_be(addr.sin6_port))))
+		},
+		_ => None,
+	}
+}
+
+/// Accept a connection, along with the address of the peer.
+fn accept(fd: RawFd) -> Option<Connection> {
+	let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+	let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+	let addr_ptr = &mut addr as *mut _ as *mut libc::sockaddr;
+
+	let fd = unsafe { libc::accept4(fd, addr_ptr, &mut len, libc::SOCK_CLOEXEC) };
+	if fd < 0 {
+		return None;
+	}
+	let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+	Some(Connection { fd, remote: unsafe { remote(&addr) } })
+}
+
+/// Instances of the service handling a connection.
+fn connections(service_name: &str, children: &Arc<Mutex<command::Children>>) -> usize {
+	children.lock().unwrap().values()
+		.filter(|child| child.connection && child.service_name == service_name)
+		.count()
+}
+
+/// Bind the sockets of the service, then start it as soon as one of them is ready.
+///
+/// With accept, every connection is accepted by the supervisor and handled by
+/// a new instance of the service.
+pub fn listen(service_name: &str, socket: &Socket, children: &Arc<Mutex<command::Children>>)
+	-> io::Result<()> {
+	let fds = sockets(service_name, socket)?;
+	let service_name = String::from(service_name);
+	let children = Arc::clone(children);
+	let (accepting, max_connections) = (socket.accept, socket.max_connections);
+
+	thread::spawn(move || loop {
+		// The service handles the sockets itself while it runs.
+		if ! accepting && service::pidfile_get(&service_name).is_some() {
+			thread::sleep(Duration::from_secs(1));
+			continue;
+		}
+
+		// Over the limit, new connections wait in the backlog.
+		if accepting && connections(&service_name, &children) >= max_connections {
+			thread::sleep(Duration::from_millis(100));
+			continue;
+		}
+
+		let mut pollfds: Vec<libc::pollfd> = fds.iter()
+			.map(|fd| libc::pollfd { fd: *fd, events: libc::POLLIN, revents: 0 })
+			.collect();
+
+		let ready = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, 1000) };
+
+		if ready > 0 && accepting {
+			for pollfd in pollfds.iter().filter(|pollfd| pollfd.revents & libc::POLLIN != 0) {
+				if connections(&service_name, &children) >= max_connections {
+					break;
+				}
+				if let Some(connection) = accept(pollfd.fd) {
+					let _ = service::start_connection(&service_name, connection, &children);
+				}
+			}
+			continue;
+		}
+
+		let idle = service::pidfile_get(&service_name).is_none();
+		if ready > 0 && idle && service::start(&service_name, &children).is_err() {
+			thread::sleep(Duration::from_secs(1));
+		}
+	});
+
+	Ok(())
+}
+
+extern "C" {
+	static mut environ: *const *const libc::c_char;
+}
+
+/// Sockets and environment passed to a service, set up in the child.
+pub struct Listen {
+	fds: Vec<RawFd>,
+	moved: Vec<RawFd>,
+	/// Strings of the environment, only used through the pointers.
+	_env: Vec<CString>,
+	listen_pid: Vec<u8>,
+	pointers: Vec<usize>,
+}
+
+/// Pass the sockets to the command, at fd 3 and up, along with the LISTEN_FDS,
+/// LISTEN_FDNAMES and LISTEN_PID variables.
+///
+/// LISTEN_PID is the pid of the child, only known after the fork. The command is
+/// rebuilt with its whole environment, which the child sets from pre_exec.
+pub fn command(process: &Command, clear_env: bool, service_name: &str, fds: Vec<RawFd>) -> (Command, Listen) {
+	let mut env: BTreeMap<OsString, OsString> = match clear_env {
+		true => BTreeMap::new(),
+		false => std::env::vars_os().collect(),
+	};
+
+	for (key, value) in process.get_envs() {
+		match value {
+			Some(value) => env.insert(key.into(), value.into()),
+			None => env.remove(key),
+		};
+	}
+
+	let names = vec![service_name; fds.len()].join(":");
+	env.insert("LISTEN_FDS".into(), fds.len().to_string().into());
+	env.insert("LISTEN_FDNAMES".into(), names.into());
+	env.remove(&OsString::from("LISTEN_PID"));
+
+	let env: Vec<CString> = env.into_iter().filter_map(|(key, value)| {
+		let mut var = key.as_bytes().to_vec();
+		var.push(b'=');
+		var.extend(value.as_bytes());
+		CString::new(var).ok()
+	}).collect();
+
+	// Room for the digits of the pid, nothing is allocated in the child.
+	let mut listen_pid = b"LISTEN_PID=".to_vec();
+	listen_pid.resize(listen_pid.len() + 12, 0);
+
+	// The pointers are kept as addresses, the strings don't move along with the vectors.
+	let mut pointers: Vec<usize> = env.iter().map(|var| var.as_ptr() as usize).collect();
+	pointers.push(listen_pid.as_ptr() as usize);
+	pointers.push(0);
+
+	let mut rebuilt = Command::new(process.get_program());
+	rebuilt.args(process.get_args());
+	if let Some(dir) = process.get_current_dir() {
+		rebuilt.current_dir(dir);
+	}
+
+	let moved = vec![0; fds.len()];
+	(rebuilt, Listen { fds, moved, _env: env, listen_pid, pointers })
+}
+
+impl Listen {
+	/// Hold the free fds where the sockets go in the child until it is spawned.
+	///
+	/// The pipe std reports exec errors through is then created above them,
+	/// the sockets can't overwrite it in the child.
+	pub fn reserve(&self) -> Vec<OwnedFd> {
+		let mut reserved = vec![];
+		let Some(socket) = self.fds.first() else {
+			return reserved;
+		};
+
+		for target in LISTEN_FDS_START .. LISTEN_FDS_START + self.fds.len() as RawFd {
+			if unsafe { libc::fcntl(target, libc::F_GETFD) } >= 0 {
+				continue;
+			}
+			let fd = unsafe { libc::fcntl(*socket, libc::F_DUPFD_CLOEXEC, target) };
+			if fd >= 0 {
+				reserved.push(unsafe { OwnedFd::from_raw_fd(fd) });
+			}
+		}
+
+		reserved
+	}
+
+	/// Place the sockets at fd 3 and up, before the sandbox restricts the syscalls.
+	pub unsafe fn fds(&mut self) -> io::Result<()> {
+		let start = LISTEN_FDS_START + self.fds.len() as RawFd;
+
+		// Move the sockets out of the way first, they might sit where the others go.
+		// The reserved fds and std's pipe are left alone, only free fds are taken.
+		for (i, fd) in self.fds.iter().enumerate() {
+			self.moved[i] = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, start);
+			if self.moved[i] < 0 {
+				return Err(io::Error::last_os_error());
+			}
+		}
+
+		// Duplicates don't inherit close-on-exec.
+		for (i, fd) in self.moved.iter().enumerate() {
+			if libc::dup2(*fd, LISTEN_FDS_START + i as RawFd) < 0 {
+				return Err(io::Error::last_os_error());
+			}
+			libc::close(*fd);
+		}
+
+		Ok(())
+	}
+
+	/// Set the environment, once the child has its final pid.
+	pub unsafe fn environ(&mut self) {
+		let digits = b"LISTEN_PID=".len();
+		let mut pid = libc::getpid();
+		let len = (pid.ilog10() + 1) as usize;
+
+		for i in (0 .. len).rev() {
+			self.listen_pid[digits + i] = b'0' + (pid % 10) as u8;
+			pid /= 10;
+		}
+
+		// The pointers point into env and listen_pid, kept alive along with them.
+		environ = self.pointers.as_ptr() as *const *const libc::c_char;
+	}
+}
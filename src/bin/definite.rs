@@ -17,7 +17,7 @@ enum Mode {
 
 static mut REBOOT_CMD: i32 = libc::LINUX_REBOOT_CMD_RESTART;
 
-extern fn on_reboot(signal: libc::c_int) {
+extern "C" fn on_reboot(signal: libc::c_int) {
 	unsafe {
 		REBOOT_CMD = if signal == libc::SIGTERM {
 			libc::LINUX_REBOOT_CMD_POWER_OFF
@@ -34,7 +34,7 @@ extern fn on_reboot(signal: libc::c_int) {
 	unsafe { libc::kill(-1, libc::SIGTERM); }
 }
 
-extern fn on_alarm(_signal: libc::c_int) {
+extern "C" fn on_alarm(_signal: libc::c_int) {
 	unsafe { libc::kill(-1, libc::SIGKILL); }
 }
 
@@ -43,7 +43,7 @@ fn list_filesystems() -> Vec<String> {
 
 	let file = fs::File::open("/proc/mounts").unwrap();
 	let reader = BufReader::new(file);
-	for line in reader.lines().flatten() {
+	for line in reader.lines().map_while(Result::ok) {
 		let mountpoint = line.split(' ').nth(1).unwrap();
 		filesystems.push(mountpoint.into());
 	}
@@ -106,6 +106,8 @@ fn definite() {
 
 	mount_filesystems();
 
+	// Reaped by the wait loop below, along with every orphaned process.
+	#[allow(clippy::zombie_processes)]
 	process::Command::new("/bin/control").arg("start-all").spawn().unwrap();
 
 	unsafe {
@@ -117,9 +119,9 @@ fn definite() {
 
 		libc::sigprocmask(libc::SIG_BLOCK, &sigset, std::ptr::null_mut());
 
-		libc::signal(libc::SIGTERM, on_reboot as extern fn(libc::c_int) as usize);
-		libc::signal(libc::SIGUSR1, on_reboot as extern fn(libc::c_int) as usize);
-		libc::signal(libc::SIGALRM, on_alarm as extern fn(libc::c_int) as usize);
+		libc::signal(libc::SIGTERM, on_reboot as extern "C" fn(libc::c_int) as usize);
+		libc::signal(libc::SIGUSR1, on_reboot as extern "C" fn(libc::c_int) as usize);
+		libc::signal(libc::SIGALRM, on_alarm as extern "C" fn(libc::c_int) as usize);
 	}
 
 	loop {
@@ -2,28 +2,35 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use super::{service, socket, table};
+use super::{activation, graph, health, notify, service, socket, table, timer, timing, validate, watch};
 use table::*;
-use std::collections::HashMap;
+use std::collections::{hash_map::Entry, BTreeSet, HashMap};
 use std::ffi::OsString;
 use std::fs;
 use std::os::unix::fs as ufs;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 use std::path::{Path, PathBuf};
-use std::process;
+use std::process::{self, ExitCode};
 use std::sync::{Arc, Mutex};
 use std::{thread, time};
 
 static mut BREAK_START_ALL_LOOP: bool = false;
 
-extern fn on_sigterm(_signal: libc::c_int) {
+/// Period over which the restarts of a service are counted against its restart-limit.
+const RESTART_LIMIT_INTERVAL: time::Duration = time::Duration::from_secs(60);
+
+extern "C" fn on_sigterm(_signal: libc::c_int) {
 	unsafe { BREAK_START_ALL_LOOP = true; }
 }
 
 fn list_directory(directory: String, extension: &str) -> Vec<String> {
 	let mut files = Vec::new();
 
-	for entry in fs::read_dir(directory).unwrap() {
+	let Ok(entries) = fs::read_dir(directory) else {
+		return files;
+	};
+
+	for entry in entries {
 		let path = entry.unwrap().path();
 		if path.extension().unwrap_or(&OsString::new()) == extension {
 			files.push(path.file_stem().unwrap().to_string_lossy().to_string());
@@ -33,9 +40,24 @@ fn list_directory(directory: String, extension: &str) -> Vec<String> {
 	files
 }
 
-pub type Children = HashMap<i32, (String, bool, bool)>;
+/// A process started by the supervisor.
+pub struct Child {
+	pub service_name: String,
+	pub restart: bool,
+	pub restart_always: bool,
+	/// Restarts allowed within a minute, the service fails once exceeded.
+	pub restart_limit: Option<usize>,
+	/// Notifications are accepted from the descendants of the process too.
+	pub notify_all: bool,
+	/// An instance handling a single connection of an accept socket.
+	pub connection: bool,
+	/// Stopped by its health checks or its watchdog, to be started again.
+	pub unhealthy: bool,
+}
 
-pub fn start_all() {
+pub type Children = HashMap<i32, Child>;
+
+pub fn start_all() -> ExitCode {
 	if ! Path::new(&service::rundir()).exists() {
 		fs::create_dir(service::rundir()).unwrap();
 	}
@@ -56,63 +78,188 @@ pub fn start_all() {
 	fs::write(&lockfile, pid).unwrap();
 
 	unsafe {
-		libc::signal(libc::SIGTERM, on_sigterm as extern fn(libc::c_int) as usize);
+		libc::signal(libc::SIGTERM, on_sigterm as extern "C" fn(libc::c_int) as usize);
 	}
 
 	let children = Arc::new(Mutex::new(Children::new()));
+	notify::listen(&children);
 
 	let mut service_names: Vec<String> = Vec::new();
 	for service_name in list_directory(service::confdir_enabled(), "toml") {
 		service_names.push(service_name);
 	}
 
+	timing::reset();
+
+	let mut timers = vec![];
+	let mut booted: Vec<String> = vec![];
+
 	let service_names = service::order(service_names);
 	for service_name in service_names {
+		if service::masked(&service_name) {
+			log_bold("Skipping masked", &service_name);
+			continue;
+		}
+
+		let service = service::load(&service_name);
+
+		// Only the first of two conflicting services is brought up.
+		if let Ok(service) = &service {
+			let conflict = booted.iter().find(|other| service::conflicts(&service_name, service, other));
+			if let Some(other) = conflict {
+				log_bold("Skipping conflicting", &format!("{service_name} (conflicts with {other})"));
+				continue;
+			}
+		}
+
+		// Socket activated services are started on the first connection.
+		if let Ok(service::Service { socket: Some(socket), .. }) = &service {
+			log_bold("Listening", &service_name);
+			match activation::listen(&service_name, socket, &children) {
+				Ok(_) => booted.push(service_name),
+				Err(err) => log_bold("Cannot listen", &format!("{service_name} ({err})")),
+			}
+			continue;
+		}
+
+		// Services of timers are started when the timer elapses.
+		if service.as_ref().is_ok_and(|service| service.timer.is_some()) {
+			log_bold("Scheduling", &service_name);
+			booted.push(service_name.clone());
+			timers.push(service_name);
+			continue;
+		}
+
+		// Services of paths are started when the paths exist or change.
+		if service.as_ref().is_ok_and(|service| service.path.is_some()) {
+			log_bold("Watching", &service_name);
+			match watch::watch(&service_name, &children) {
+				Ok(_) => booted.push(service_name),
+				Err(err) => log_bold("Cannot watch", &format!("{service_name} ({err})")),
+			}
+			continue;
+		}
+
 		log_bold("Starting", &service_name);
-		let _ = service::start(&service_name, &children);
+		timing::record("spawn", &service_name);
+
+		// One-time services run until they exit, they are ready once started, like the
+		// services not sending READY=1. The others are ready once they sent it.
+		// Services that didn't come up leave room for the services they conflict with.
+		let notify_ready = service.is_ok_and(|service| service.control.notify_ready);
+		match service::start(&service_name, &children) {
+			Ok(pid) => {
+				if pid == 0 || ! notify_ready {
+					timing::record("ready", &service_name);
+				}
+				booted.push(service_name);
+			},
+			Err(service::Error::Condition | service::Error::Assertion) => {
+				let failed = service::condition(&service_name).unwrap_or_default();
+				let (failed, check) = failed.split_once(": ").unwrap_or((&failed, ""));
+				log_bold(failed, &format!("{service_name} ({check})"));
+			},
+			Err(_) => {},
+		}
 	}
 
+	timer::run(timers, &children);
 	socket::listen(&children);
 
+	let mut pidfds: HashMap<i32, OwnedFd> = HashMap::new();
+	let mut restarts: HashMap<String, Vec<time::Instant>> = HashMap::new();
+
 	loop {
-		let (pid, dirty) = unsafe {
-			let mut wstatus: libc::c_int = 0;
-			let pid = libc::wait(&mut wstatus);
+		if unsafe { BREAK_START_ALL_LOOP } {
+			break;
+		}
 
-			if pid < 0 {
-				thread::sleep(time::Duration::from_millis(100));
-				if BREAK_START_ALL_LOOP { break; } else { continue; }
-			}
+		for (pid, wstatus) in wait_children(&children, &mut pidfds) {
+			let dirty = if libc::WIFEXITED(wstatus) {
+				libc::WEXITSTATUS(wstatus) != 0
+			} else if libc::WIFSIGNALED(wstatus) {
+				libc::WCOREDUMP(wstatus)
+			} else {
+				false
+			};
 
-			let dirty = {
-				if libc::WIFEXITED(wstatus) {
-					libc::WEXITSTATUS(wstatus) != 0
-				} else if libc::WIFSIGNALED(wstatus) {
-					libc::WCOREDUMP(wstatus)
-				} else {
-					false
-				}
+			let mut children_ref = children.lock().unwrap();
+			let Some(child) = children_ref.remove(&pid) else {
+				continue;
 			};
+			drop(children_ref);
 
-			(pid, dirty)
-		};
+			// Connections come and go, the service keeps listening.
+			if child.connection {
+				continue;
+			}
+
+			service::pidfile_del(&child.service_name);
+			fs::remove_file(service::healthfile(&child.service_name)).ok();
+
+			if (dirty && child.restart) || child.restart_always || child.unhealthy {
+				let starts = restarts.entry(child.service_name.clone()).or_default();
+				starts.retain(|start| start.elapsed() < RESTART_LIMIT_INTERVAL);
 
-		let mut children_ref = children.lock().unwrap();
-		let child = children_ref.remove_entry(&pid).unwrap().1;
-		let (service_name, restart, restart_always) = child;
-		service::pidfile_del(&service_name);
-		drop(children_ref);
+				if child.restart_limit.is_some_and(|limit| starts.len() >= limit) {
+					let reason = format!("restarted {} times within a minute", starts.len());
+					restarts.remove(&child.service_name);
+					service::failed(&child.service_name, &reason, &children, true);
+					continue;
+				}
 
-		if (dirty && restart) || restart_always {
-			let _ = service::start(&service_name, &children);
+				starts.push(time::Instant::now());
+				let _ = service::start(&child.service_name, &children);
+			}
 		}
 	}
 
 	socket::socket_del();
+	fs::remove_file(service::notify_sock()).ok();
+	fs::remove_file(service::timersfile()).ok();
 	fs::remove_file(lockfile).unwrap();
+	ExitCode::SUCCESS
 }
 
-pub fn stop_all() {
+/// Wait a moment for supervised processes to exit, they are reaped along with their wait status.
+///
+/// Only the supervised processes are reaped here, the threads spawning other
+/// processes, like one-time services or commands, wait for theirs.
+fn wait_children(children: &Arc<Mutex<Children>>, pidfds: &mut HashMap<i32, OwnedFd>) -> Vec<(i32, libc::c_int)> {
+	let pids: Vec<i32> = children.lock().unwrap().keys().copied().collect();
+	pidfds.retain(|pid, _| pids.contains(pid));
+
+	for pid in pids {
+		if let Entry::Vacant(entry) = pidfds.entry(pid) {
+			let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+			if fd >= 0 {
+				entry.insert(unsafe { OwnedFd::from_raw_fd(fd as RawFd) });
+			}
+		}
+	}
+
+	let (pids, mut pollfds): (Vec<i32>, Vec<libc::pollfd>) = pidfds.iter()
+		.map(|(pid, fd)| (*pid, libc::pollfd { fd: fd.as_raw_fd(), events: libc::POLLIN, revents: 0 }))
+		.unzip();
+
+	// New processes are picked up on the next round, SIGTERM interrupts the poll.
+	let ready = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, 100) };
+	if ready <= 0 {
+		return vec![];
+	}
+
+	let mut exited = vec![];
+	for (pid, pollfd) in pids.into_iter().zip(pollfds) {
+		let mut wstatus: libc::c_int = 0;
+		if pollfd.revents != 0 && unsafe { libc::waitpid(pid, &mut wstatus, libc::WNOHANG) } == pid {
+			exited.push((pid, wstatus));
+		}
+	}
+
+	exited
+}
+
+pub fn stop_all() -> ExitCode {
 	let mut service_names: Vec<String> = Vec::new();
 	for service_name in list_directory(service::confdir_enabled(), "toml") {
 		service_names.push(service_name);
@@ -128,7 +275,16 @@ pub fn stop_all() {
 
 	for service_name in service_names {
 		let meta = service::meta(&service_name);
-		let service = meta.service.unwrap();
+
+		if meta.masked {
+			log_bold("Skipping masked", &service_name);
+			continue;
+		}
+
+		let Ok(service) = meta.service else {
+			log_bold("Skipping invalid", &service_name);
+			continue;
+		};
 
 		if service.control.one_time || meta.running {
 			log_bold("Stopping", &service_name);
@@ -152,15 +308,17 @@ pub fn stop_all() {
 	while lockfile.exists() {
 		thread::sleep(time::Duration::from_millis(10));
 	}
+
+	ExitCode::SUCCESS
 }
 
-pub fn restart_all() {
+pub fn restart_all() -> ExitCode {
 	stop_all();
-	start_all();
+	start_all()
 }
 
-pub fn start(service_names: Vec<String>) {
-	let mut table = Table::new();
+pub fn start(service_names: Vec<String>) -> ExitCode {
+	let mut table = Table::new().keys(&["service", "state"]);
 
 	for service_name in service_names {
 		table.first(&service_name);
@@ -168,12 +326,22 @@ pub fn start(service_names: Vec<String>) {
 		let meta = service::meta(&service_name);
 
 		if ! meta.exists {
-			table.field("Service not exists", RED);
+			table.error("Service not exists");
+			continue;
+		}
+
+		if meta.masked {
+			table.error("Masked");
+			continue;
+		}
+
+		if meta.template {
+			table.error("Template, specify an instance");
 			continue;
 		}
 
 		if ! meta.valid {
-			table.field("Invalid service", RED);
+			table.error("Invalid service");
 			continue;
 		}
 
@@ -182,27 +350,46 @@ pub fn start(service_names: Vec<String>) {
 			continue;
 		}
 
+		let accept = meta.service.is_ok_and(|service| service.socket.is_some_and(|socket| socket.accept));
+		if accept {
+			table.field("Only started per connection", YELLOW);
+			continue;
+		}
+
 		let result = service::start_socket(&service_name);
 
-		if let Err(err) = result {
-			if err == service::Error::NotFound {
-				table.field("Cannot start", RED);
+		if let Err(err) = &result {
+			// Skipped services are not an error, unlike failed assertions.
+			if let Some(failed) = service::condition(&service_name) {
+				match failed.starts_with("Condition") {
+					true => table.field(&failed, YELLOW),
+					false => table.error(&failed),
+				};
 				continue;
 			}
 
-			if err == service::Error::NoDaemon {
+			if *err == service::Error::NotFound {
+				table.error("Cannot start");
+				continue;
+			}
+
+			if *err == service::Error::NoDaemon {
 				return table_err("control", "Daemon is not running");
 			}
 		};
 
-		table.field("Started", GREEN);
+		match result {
+			Ok(0) => table.field("Finished", GREEN),
+			_ => table.field("Started", GREEN),
+		};
 	}
 
 	table.print();
+	table.status()
 }
 
-pub fn stop(service_names: Vec<String>) {
-	let mut table = Table::new();
+pub fn stop(service_names: Vec<String>) -> ExitCode {
+	let mut table = Table::new().keys(&["service", "state"]);
 
 	for service_name in service_names {
 		table.first(&service_name);
@@ -210,12 +397,12 @@ pub fn stop(service_names: Vec<String>) {
 		let meta = service::meta(&service_name);
 
 		if ! meta.exists {
-			table.field("Service not exists", RED);
+			table.error("Service not exists");
 			continue;
 		}
 
 		if ! meta.valid {
-			table.field("Invalid service", RED);
+			table.error("Invalid service");
 			continue;
 		}
 
@@ -237,15 +424,16 @@ pub fn stop(service_names: Vec<String>) {
 		if result.is_ok() {
 			table.field("Stopped", GREEN);
 		} else {
-			table.field("Cannot stop", RED);
+			table.error("Cannot stop");
 		}
 	}
 
 	table.print();
+	table.status()
 }
 
-pub fn restart(service_names: Vec<String>) {
-	let mut table = Table::new();
+pub fn restart(service_names: Vec<String>) -> ExitCode {
+	let mut table = Table::new().keys(&["service", "state"]);
 
 	for service_name in service_names {
 		table.first(&service_name);
@@ -253,12 +441,12 @@ pub fn restart(service_names: Vec<String>) {
 		let meta = service::meta(&service_name);
 
 		if ! meta.exists {
-			table.field("Service not exists", RED);
+			table.error("Service not exists");
 			continue;
 		}
 
 		if ! meta.valid {
-			table.field("Invalid service", RED);
+			table.error("Invalid service");
 			continue;
 		}
 
@@ -273,13 +461,13 @@ pub fn restart(service_names: Vec<String>) {
 
 		if let Err(err) = result {
 			if err != service::Error::NotFound {
-				table.field("Cannot restart", RED);
+				table.error("Cannot restart");
 				continue;
 			}
 
 			let result = service::stop(&service, meta.pid);
 			if result.is_err() {
-				table.field("Cannot stop", RED);
+				table.error("Cannot stop");
 				continue;
 			}
 
@@ -290,7 +478,7 @@ pub fn restart(service_names: Vec<String>) {
 
 			let result = service::start_socket(&service_name);
 			if result.is_err() {
-				table.field("Cannot start", RED);
+				table.error("Cannot start");
 				continue;
 			}
 		}
@@ -299,10 +487,11 @@ pub fn restart(service_names: Vec<String>) {
 	}
 
 	table.print();
+	table.status()
 }
 
-pub fn reload(service_names: Vec<String>) {
-	let mut table = Table::new();
+pub fn reload(service_names: Vec<String>) -> ExitCode {
+	let mut table = Table::new().keys(&["service", "state"]);
 
 	for service_name in service_names {
 		table.first(&service_name);
@@ -310,12 +499,12 @@ pub fn reload(service_names: Vec<String>) {
 		let meta = service::meta(&service_name);
 
 		if ! meta.exists {
-			table.field("Service not exists", RED);
+			table.error("Service not exists");
 			continue;
 		}
 
 		if ! meta.valid {
-			table.field("Invalid service", RED);
+			table.error("Invalid service");
 			continue;
 		}
 
@@ -331,15 +520,16 @@ pub fn reload(service_names: Vec<String>) {
 		if result.is_ok() {
 			table.field("Reloaded", GREEN);
 		} else {
-			table.field("Cannot reload", RED);
+			table.error("Cannot reload");
 		}
 	}
 
 	table.print();
+	table.status()
 }
 
-pub fn enable(service_names: Vec<String>) {
-	let mut table = Table::new();
+pub fn enable(service_names: Vec<String>) -> ExitCode {
+	let mut table = Table::new().keys(&["service", "state"]);
 
 	for service_name in service_names {
 		table.first(&service_name);
@@ -347,12 +537,22 @@ pub fn enable(service_names: Vec<String>) {
 		let meta = service::meta(&service_name);
 
 		if ! meta.exists {
-			table.field("Service not exists", RED);
+			table.error("Service not exists");
+			continue;
+		}
+
+		if meta.masked {
+			table.error("Masked");
+			continue;
+		}
+
+		if meta.template {
+			table.error("Template, specify an instance");
 			continue;
 		}
 
 		if ! meta.valid {
-			table.field("Invalid service", RED);
+			table.error("Invalid service");
 			continue;
 		}
 
@@ -365,20 +565,26 @@ pub fn enable(service_names: Vec<String>) {
 			fs::create_dir(service::confdir_enabled()).unwrap();
 		}
 
-		let service_from = format!("../{service_name}.toml");
+		// Files of the admin directory are linked relatively, so it can be moved.
+		let service_file = service::service_file(&service_name);
+		let service_from = match service_file.strip_prefix(&format!("{}/", service::confdir())) {
+			Some(file_name) => format!("../{file_name}"),
+			None => service_file.clone(),
+		};
 		let service_to = format!("{}/{service_name}.toml", service::confdir_enabled());
 
 		match ufs::symlink(service_from, service_to) {
 			Ok(_) => table.field("Enabled", GREEN),
-			Err(err) => table.field(&err.to_string(), RED),
+			Err(err) => table.error(&err.to_string()),
 		};
 	}
 
 	table.print();
+	table.status()
 }
 
-pub fn disable(service_names: Vec<String>) {
-	let mut table = Table::new();
+pub fn disable(service_names: Vec<String>) -> ExitCode {
+	let mut table = Table::new().keys(&["service", "state"]);
 
 	for service_name in service_names {
 		table.first(&service_name);
@@ -386,7 +592,7 @@ pub fn disable(service_names: Vec<String>) {
 		let meta = service::meta(&service_name);
 
 		if ! meta.exists {
-			table.field("Service not exists", RED);
+			table.error("Service not exists");
 			continue;
 		}
 
@@ -399,27 +605,109 @@ pub fn disable(service_names: Vec<String>) {
 
 		match fs::remove_file(service_file) {
 			Ok(_) => table.field("Disabled", GREEN),
-			Err(err) => table.field(&err.to_string(), RED),
+			Err(err) => table.error(&err.to_string()),
+		};
+	}
+
+	table.print();
+	table.status()
+}
+
+pub fn mask(service_names: Vec<String>) -> ExitCode {
+	let mut table = Table::new().keys(&["service", "state", "file"]);
+
+	for service_name in service_names {
+		table.first(&service_name);
+
+		if service::masked(&service_name) {
+			table.field("Already masked", YELLOW);
+			continue;
+		}
+
+		let maskfile = service::maskfile(&service_name);
+		if ! Path::new(&service::confdir_masked()).exists() {
+			fs::create_dir_all(service::confdir_masked()).unwrap();
+		}
+
+		if let Err(err) = ufs::symlink("/dev/null", &maskfile) {
+			table.error(&err.to_string());
+			continue;
+		}
+
+		// A masked service is not started at boot anyway.
+		let enabled = format!("{}/{service_name}.toml", service::confdir_enabled());
+		match fs::remove_file(enabled) {
+			Ok(_) => table.field("Masked and disabled", GREEN),
+			Err(_) => table.field("Masked", GREEN),
 		};
 	}
 
 	table.print();
+	table.status()
 }
 
-pub fn status(service_name: Option<String>) {
+pub fn unmask(service_names: Vec<String>) -> ExitCode {
+	let mut table = Table::new().keys(&["service", "state", "file"]);
+
+	for service_name in service_names {
+		table.first(&service_name);
+
+		if ! service::masked(&service_name) {
+			table.field("Not masked", YELLOW);
+			continue;
+		}
+
+		let maskfile = service::maskfile(&service_name);
+		let masked_here = fs::read_link(&maskfile).is_ok_and(|path| path == Path::new("/dev/null"));
+
+		if ! masked_here {
+			let service_file = service::service_file(&service_name);
+			table.error("Masked by another file").field(&service_file, MUTED);
+			continue;
+		}
+
+		match fs::remove_file(maskfile) {
+			Ok(_) => table.field("Unmasked", GREEN),
+			Err(err) => table.error(&err.to_string()),
+		};
+	}
+
+	table.print();
+	table.status()
+}
+
+/// All services, without the templates.
+fn known_services() -> Vec<String> {
 	let mut service_names: Vec<String> = Vec::new();
 
-	if let Some(service_name) = service_name {
-		service_names.push(service_name);
-	} else {
-		for service_name in list_directory(service::confdir(), "toml") {
+	for service_name in service::list().into_keys() {
+		if ! service_name.ends_with('@') {
+			service_names.push(service_name);
+		}
+	}
+
+	// Instances only exist once they are enabled or running.
+	let enabled = list_directory(service::confdir_enabled(), "toml");
+	let running = list_directory(service::rundir(), "pid");
+	for service_name in enabled.into_iter().chain(running) {
+		if service::instance(&service_name).is_some() {
 			service_names.push(service_name);
 		}
 	}
 
 	service_names.sort();
+	service_names.dedup();
+	service_names
+}
 
-	let mut table = Table::new();
+pub fn status(service_name: Option<String>, verbose: bool) -> ExitCode {
+	let service_names = match service_name {
+		Some(service_name) => vec![service_name],
+		None => known_services(),
+	};
+
+	let mut table = Table::new().keys(&["service", "state", "running", "origin"]);
+	let mut details = vec![];
 
 	for service_name in service_names {
 		table.first(&service_name);
@@ -427,12 +715,20 @@ pub fn status(service_name: Option<String>) {
 		let meta = service::meta(&service_name);
 
 		if ! meta.exists {
-			table.field("Not exists", RED).empty(1);
+			table.error("Not exists").empty(2);
+			continue;
+		}
+
+		let service_file = service::service_file(&service_name);
+		let origin = Path::new(&service_file).parent().unwrap().to_string_lossy().to_string();
+
+		if meta.masked {
+			table.field("Masked", RED).empty(1).field(&origin, MUTED);
 			continue;
 		}
 
 		if ! meta.valid {
-			table.field("Invalid", RED).empty(1);
+			table.error("Invalid").empty(1).field(&origin, MUTED);
 			continue;
 		}
 
@@ -444,19 +740,73 @@ pub fn status(service_name: Option<String>) {
 
 		let service = meta.service.unwrap();
 
-		if service.control.one_time {
+		let failed = service::condition(&service_name).filter(|_| ! meta.running);
+
+		if let Some(failed) = failed {
+			match failed.starts_with("Condition") {
+				true => table.field(&failed, YELLOW),
+				false => table.error(&failed),
+			};
+		} else if service.control.one_time {
 			table.field("One time", GREEN);
 		} else if meta.running {
-			table.field("Running", GREEN);
+			match health::state(&service_name).as_deref() {
+				Some("healthy") => table.field("Running (healthy)", GREEN),
+				Some("unhealthy") => table.error("Running (unhealthy)"),
+				_ => table.field("Running", GREEN),
+			};
 		} else {
 			table.field("Not running", YELLOW);
 		}
+
+		table.field(&origin, MUTED);
+
+		if verbose {
+			let mut properties = vec![];
+			if let Some(pid) = meta.pid {
+				properties.push((String::from("PID"), pid.to_string()));
+			}
+			for (key, value) in service.properties() {
+				properties.push((key.to_string(), value));
+			}
+
+			let mut detail = Table::new();
+			detail.first("Service").field(&service_name, NORMAL);
+			for (key, value) in &properties {
+				detail.first(key).field(value, NORMAL);
+			}
+			table.details(properties);
+			details.push(detail);
+		}
 	}
 
 	table.print();
+
+	// The json rows carry their details.
+	if output() != Output::Json {
+		for detail in details {
+			detail.print();
+		}
+	}
+
+	table.status()
 }
 
-pub fn check(service_name: Option<String>) {
+/// Diagnostics as rows, for the plain and json outputs.
+fn diagnostics_table(diagnostics: &[validate::Diagnostic]) -> Table {
+	let mut table = Table::new().keys(&["file", "line", "column", "message"]);
+
+	for diagnostic in diagnostics {
+		table.first(&diagnostic.file)
+			.field(&diagnostic.line.to_string(), NORMAL)
+			.field(&diagnostic.col.to_string(), NORMAL)
+			.error(&diagnostic.message);
+	}
+
+	table
+}
+
+pub fn check(service_name: Option<String>) -> ExitCode {
 	if let Some(service_name) = service_name {
 		let meta = service::meta(&service_name);
 
@@ -464,29 +814,453 @@ pub fn check(service_name: Option<String>) {
 			return table_err(&service_name, "Not exists");
 		}
 
+		if meta.masked {
+			return table_err(&service_name, "Masked");
+		}
+
+		let diagnostics = validate::check(&service_name);
+
+		if output() != Output::Table {
+			if diagnostics.is_empty() {
+				let mut table = Table::new().keys(&["service", "state"]);
+				table.first(&service_name).field("OK", GREEN);
+				table.print();
+				return table.status();
+			}
+			let table = diagnostics_table(&diagnostics);
+			table.print();
+			return table.status();
+		}
+
+		let status = match meta.service.is_ok() && diagnostics.is_empty() {
+			true => ExitCode::SUCCESS,
+			false => ExitCode::FAILURE,
+		};
+
 		let check = match meta.service {
-			Ok(service) => format!("{:#?}\n", service),
-			Err(err) => err.to_string(),
+			Ok(service) if diagnostics.is_empty() => format!("{:#?}\n", service),
+			_ => diagnostics.iter().map(|diagnostic| paint(&diagnostic.to_string(), RED) + "\n").collect(),
 		};
 
 		print!("\n  {}\n", check.replace('\n', "\n  "));
+		status
 	} else {
-		let mut table = Table::new();
+		let mut table = Table::new().keys(&["file", "state"]);
+		let mut diagnostics = vec![];
 
-		let mut service_names = list_directory(service::confdir(), "toml");
-		service_names.sort();
-
-		for service_name in service_names {
-			let confdir = format!("{}/", service::confdir());
-			table.ppfirst(&confdir, &service_name, ".toml");
+		for (service_name, service_file) in service::list() {
+			let confdir = Path::new(&service_file).parent().unwrap().to_string_lossy();
+			table.ppfirst(&format!("{confdir}/"), &service_name, ".toml");
 
 			let meta = service::meta(&service_name);
-			match meta.valid {
-				true => table.field("OK", GREEN),
-				false => table.field("Invalid", RED),
+			if meta.masked {
+				table.field("Masked", YELLOW);
+				continue;
+			}
+
+			let problems = validate::check(&service_name);
+			match (meta.valid, problems.len()) {
+				(true, 0) => table.field("OK", GREEN),
+				(true, 1) => table.error("1 problem"),
+				(true, len) => table.error(&format!("{len} problems")),
+				(false, _) => table.error("Invalid"),
 			};
+			diagnostics.extend(problems);
 		}
 
 		table.print();
+
+		if output() != Output::Table {
+			diagnostics_table(&diagnostics).print();
+		} else if ! diagnostics.is_empty() {
+			let diagnostics: Vec<_> = diagnostics.iter().map(|d| d.to_string()).collect();
+			println!("  {}\n", diagnostics.join("\n  "));
+		}
+
+		table.status()
+	}
+}
+
+fn toml_key(key: &str) -> String {
+	if ! key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+		key.into()
+	} else {
+		toml::Value::String(key.into()).to_string()
+	}
+}
+
+pub fn cat(service_name: String) -> ExitCode {
+	let meta = service::meta(&service_name);
+
+	if ! meta.exists {
+		return table_err(&service_name, "Not exists");
+	}
+
+	let (table, sources) = match service::merged(&service_name) {
+		Ok(merged) => merged,
+		Err(err) if output() == Output::Table => {
+			print!("\n  {}\n", paint(&err.to_string(), RED).replace('\n', "\n  "));
+			return ExitCode::FAILURE;
+		},
+		Err(err) => return table_err(&service_name, err.message()),
+	};
+
+	let source = |path: &str| {
+		let prefix = format!("{path}.");
+		sources.iter().find(|(key, _)| key == path || key.starts_with(&prefix))
+			.map(|(_, source)| source.as_str()).unwrap_or("")
+	};
+
+	let mut sections: Vec<(String, Vec<(String, &str)>)> = vec![(String::new(), vec![])];
+	let mut keys = Table::new().keys(&["key", "value", "source"]);
+
+	for (key, value) in &table {
+		if let toml::Value::Table(section) = value {
+			let lines = section.iter().map(|(name, value)| {
+				let path = format!("{key}.{name}");
+				keys.first(&path).field(&value.to_string(), NORMAL).field(source(&path), MUTED);
+				let line = format!("{} = {value}", toml_key(name));
+				(line, source(&path))
+			}).collect();
+			sections.push((format!("[{}]", toml_key(key)), lines));
+		} else {
+			keys.first(key).field(&value.to_string(), NORMAL).field(source(key), MUTED);
+			let line = format!("{} = {value}", toml_key(key));
+			sections[0].1.push((line, source(key)));
+		}
+	}
+
+	if output() != Output::Table {
+		keys.print();
+		return ExitCode::SUCCESS;
+	}
+
+	let width = sections.iter().flat_map(|(_, lines)| lines)
+		.map(|(line, _)| table::width(line)).max().unwrap_or(0);
+
+	let mut cat = String::new();
+
+	for service_file in service::service_files(&service_name) {
+		if Path::new(&service_file).exists() {
+			cat.push_str(&format!("# {service_file}\n"));
+		}
+	}
+
+	for (header, lines) in sections {
+		if header.is_empty() && lines.is_empty() {
+			continue;
+		}
+
+		cat.push('\n');
+		if ! header.is_empty() {
+			cat.push_str(&format!("{header}\n"));
+		}
+		for (line, source) in lines {
+			cat.push_str(&format!("{}  # {source}\n", table::pad(&line, width)));
+		}
+	}
+
+	print!("\n  {}\n", cat.replace('\n', "\n  "));
+	ExitCode::SUCCESS
+}
+
+fn deps_tree(graph: &graph::Graph, service_name: &str, reverse: bool, prefix: &str,
+	path: &mut Vec<String>, tree: &mut String, table: &mut Table) {
+	let next = match reverse {
+		true => graph.reverse(service_name),
+		false => graph.depends(service_name),
+	};
+
+	path.push(service_name.into());
+
+	for (i, name) in next.iter().enumerate() {
+		let last = i + 1 == next.len();
+		let branch = if last { "└─ " } else { "├─ " };
+		tree.push_str(&format!("{prefix}{branch}{}", paint(name, NORMAL)));
+		table.first(service_name).field(name, NORMAL);
+
+		if path.contains(name) {
+			tree.push_str(&format!("  {}\n", paint("cycle", RED)));
+			table.field("cycle", RED);
+			continue;
+		}
+
+		if let Some(problem) = graph.problem(name) {
+			tree.push_str(&format!("  {}", paint(problem, RED)));
+			table.field(problem, RED);
+		}
+		tree.push('\n');
+
+		let prefix = format!("{prefix}{}", if last { "   " } else { "│  " });
+		deps_tree(graph, name, reverse, &prefix, path, tree, table);
+	}
+
+	path.pop();
+}
+
+/// Graphviz graph of the services, missing services are dashed and cycles red.
+fn dot(graph: &graph::Graph, service_names: &BTreeSet<String>, ranks: &[Vec<String>]) {
+	let cycles = graph.cycles();
+	let mut dot = String::from("digraph control {\n");
+
+	for service_name in service_names {
+		match graph.problem(service_name) {
+			Some(problem) => {
+				let label = format!("{service_name}\\n({problem})");
+				dot.push_str(&format!("\t{service_name:?} [style=dashed, label=\"{label}\"];\n"));
+			},
+			None => dot.push_str(&format!("\t{service_name:?};\n")),
+		}
+	}
+
+	for (from, to) in graph.edges() {
+		if ! service_names.contains(from) || ! service_names.contains(to) {
+			continue;
+		}
+
+		if cycles.contains(&(from.into(), to.into())) {
+			dot.push_str(&format!("\t{from:?} -> {to:?} [color=red];\n"));
+		} else {
+			dot.push_str(&format!("\t{from:?} -> {to:?};\n"));
+		}
+	}
+
+	for rank in ranks {
+		let rank: Vec<_> = rank.iter().map(|name| format!("{name:?}")).collect();
+		dot.push_str(&format!("\t{{ rank=same; {}; }}\n", rank.join("; ")));
+	}
+
+	dot.push_str("}\n");
+	print!("{dot}");
+}
+
+pub fn deps(service_name: String, reverse: bool, dot_output: bool) -> ExitCode {
+	let graph = match reverse {
+		true => graph::Graph::new(&known_services()),
+		false => graph::Graph::new(std::slice::from_ref(&service_name)),
+	};
+
+	if dot_output {
+		// Only the services reachable from the service.
+		let mut service_names = BTreeSet::new();
+		let mut queue = vec![service_name];
+		while let Some(name) = queue.pop() {
+			if service_names.insert(name.clone()) {
+				queue.extend(if reverse { graph.reverse(&name) } else { graph.depends(&name) });
+			}
+		}
+		dot(&graph, &service_names, &[]);
+		return ExitCode::SUCCESS;
+	}
+
+	let keys = if reverse { ["service", "required-by", "problem"] } else { ["service", "depends", "problem"] };
+	let mut table = Table::new().keys(&keys);
+
+	let mut tree = paint(&service_name, BOLD);
+	if let Some(problem) = graph.problem(&service_name) {
+		tree.push_str(&format!("  {}", paint(problem, RED)));
+		table.first(&service_name).empty(1).field(problem, RED);
 	}
+	tree.push('\n');
+	deps_tree(&graph, &service_name, reverse, "", &mut vec![], &mut tree, &mut table);
+
+	match output() {
+		Output::Table => print!("\n  {}\n", tree.replace('\n', "\n  ")),
+		_ => table.print(),
+	}
+
+	ExitCode::SUCCESS
+}
+
+pub fn order(dot_output: bool) -> ExitCode {
+	let mut service_names = list_directory(service::confdir_enabled(), "toml");
+	service_names.sort();
+
+	let graph = graph::Graph::new(&service_names);
+	let (levels, cyclic) = graph.levels(&service_names);
+
+	if dot_output {
+		let all = graph.edges().into_iter().flat_map(|(from, to)| [from, to]).map(String::from);
+		let all = all.chain(service_names).collect();
+		dot(&graph, &all, &levels);
+		return ExitCode::SUCCESS;
+	}
+
+	let mut table = Table::new().keys(&["level", "service", "notes"]);
+
+	let rows = levels.iter().enumerate().flat_map(|(level, names)| {
+		names.iter().map(move |name| (level.to_string(), name))
+	});
+	let rows = rows.chain(cyclic.iter().map(|name| ("-".to_string(), name)));
+
+	for (level, service_name) in rows {
+		table.first(&level).field(service_name, NORMAL);
+
+		let mut notes = vec![];
+		let mut color = YELLOW;
+		if let Some(problem) = graph.problem(service_name) {
+			notes.push(format!("Service {problem}"));
+			color = RED;
+		}
+		if cyclic.contains(service_name) {
+			notes.push("Blocked by a dependency cycle".into());
+			color = RED;
+		}
+		for depend in graph.depends(service_name) {
+			if let Some(problem) = graph.problem(&depend) {
+				notes.push(format!("Depends on {problem} {depend}"));
+				color = RED;
+			} else if ! service_names.contains(&depend) {
+				notes.push(format!("Depends on disabled {depend}"));
+			}
+		}
+
+		table.field(&notes.join(", "), color);
+	}
+
+	table.print();
+	ExitCode::SUCCESS
+}
+
+pub fn blame(svg: bool) -> ExitCode {
+	let mut timings = timing::load();
+
+	if timings.is_empty() {
+		return table_err("control", "No boot recorded");
+	}
+
+	if svg {
+		print!("{}", timing::svg(&timings));
+		return ExitCode::SUCCESS;
+	}
+
+	timings.sort_by_key(|timing| std::cmp::Reverse(timing.duration()));
+
+	let mut table = Table::new().keys(&["service", "duration", "spawn"]);
+
+	for timing in timings {
+		table.first(&timing.service_name);
+		match timing.duration() {
+			Some(duration) => table.field(&timing::format(duration), NORMAL),
+			None => table.field("Not ready", YELLOW),
+		};
+		table.field(&format!("@{}", timing::format(timing.spawn)), MUTED);
+	}
+
+	table.print();
+	ExitCode::SUCCESS
+}
+
+/// The chain of dependencies ending at the service, each one being the dependency
+/// that became ready last.
+fn chain(graph: &graph::Graph, timings: &[timing::Timing], service_name: &str,
+	prefix: &str, path: &mut Vec<String>, tree: &mut String, table: &mut Table) {
+	let timing = |name: &str| timings.iter().find(|timing| timing.service_name == name);
+
+	let latest = graph.depends(service_name).into_iter()
+		.filter(|depend| ! path.contains(depend))
+		.filter_map(|depend| timing(&depend).and_then(|timing| timing.ready).map(|ready| (ready, depend)))
+		.max();
+
+	if let Some((_, depend)) = latest {
+		let timing = timing(&depend).unwrap();
+		let ready = timing.ready.unwrap_or(timing.spawn);
+		let duration = timing.duration().unwrap_or(0);
+		tree.push_str(&format!("{prefix}└─ {} @{} +{}\n", paint(&depend, NORMAL),
+			timing::format(ready), paint(&timing::format(duration), RED)));
+		table.first(&path[0]).field(&depend, NORMAL)
+			.field(&timing::format(ready), NORMAL).field(&timing::format(duration), RED);
+
+		path.push(depend.clone());
+		chain(graph, timings, &depend, &format!("{prefix}   "), path, tree, table);
+	}
+}
+
+pub fn critical_chain(service_name: Option<String>) -> ExitCode {
+	let timings = timing::load();
+
+	if timings.is_empty() {
+		return table_err("control", "No boot recorded");
+	}
+
+	let booted: Vec<String> = timings.iter().map(|timing| timing.service_name.clone()).collect();
+	let graph = graph::Graph::new(&booted);
+
+	// Without a service, the chains of the services nothing depends on.
+	let service_names = match service_name {
+		Some(service_name) => vec![service_name],
+		None => booted.iter().filter(|name| graph.reverse(name).iter().all(|r| ! booted.contains(r)))
+			.cloned().collect(),
+	};
+
+	let mut tree = String::new();
+	let mut table = Table::new().keys(&["chain", "service", "ready", "duration"]);
+
+	for service_name in service_names {
+		table.first(&service_name).field(&service_name, NORMAL);
+
+		let Some(timing) = timings.iter().find(|timing| timing.service_name == service_name) else {
+			tree.push_str(&format!("{}  {}\n", paint(&service_name, BOLD), paint("not booted", YELLOW)));
+			table.field("not booted", YELLOW);
+			continue;
+		};
+
+		let ready = match timing.ready {
+			Some(ready) => {
+				table.field(&timing::format(ready), NORMAL).field(&timing::format(ready - timing.spawn), NORMAL);
+				format!("@{} +{}", timing::format(ready), timing::format(ready - timing.spawn))
+			},
+			None => {
+				table.field("not ready", YELLOW);
+				format!("@{} {}", timing::format(timing.spawn), paint("not ready", YELLOW))
+			},
+		};
+
+		tree.push_str(&format!("{} {ready}\n", paint(&service_name, BOLD)));
+		chain(&graph, &timings, &service_name, "", &mut vec![service_name.clone()], &mut tree, &mut table);
+	}
+
+	match output() {
+		Output::Table => print!("\n  {}\n", tree.replace('\n', "\n  ")),
+		_ => table.print(),
+	}
+
+	ExitCode::SUCCESS
+}
+
+pub fn timers() -> ExitCode {
+	let elapses = timer::elapses();
+	let now = timer::now();
+
+	let mut table = Table::new().keys(&["service", "next", "left", "last", "passed"]);
+
+	for service_name in known_services() {
+		let meta = service::meta(&service_name);
+		let Ok(service) = &meta.service else {
+			continue;
+		};
+
+		if service.timer.is_none() || meta.masked {
+			continue;
+		}
+
+		table.first(&service_name);
+
+		match elapses.iter().find(|(name, _)| *name == service_name) {
+			Some((_, next)) => table.field(&timer::format_time(*next), NORMAL)
+				.field(&timer::format_span(next.saturating_sub(now)), NORMAL),
+			None if meta.running => table.field("Running", GREEN).empty(1),
+			None if meta.enabled => table.field("Not scheduled", YELLOW).empty(1),
+			None => table.field("Disabled", YELLOW).empty(1),
+		};
+
+		match timer::stamp(&service_name) {
+			Some(last) => table.field(&timer::format_time(last), NORMAL)
+				.field(&format!("{} ago", timer::format_span(now.saturating_sub(last))), MUTED),
+			None => table.field("Never", MUTED).empty(1),
+		};
+	}
+
+	table.print();
+	ExitCode::SUCCESS
 }
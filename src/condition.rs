@@ -0,0 +1,195 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::service;
+use std::ffi::CStr;
+use std::fs;
+use std::path::Path;
+use serde::Deserialize;
+
+/// Checks made before starting a service, used both by conditions and assertions.
+///
+/// Every check must hold, a "!" prefix negates a check.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Conditions {
+	#[serde(alias="path-exists", default="service::xvec")]
+	path_exists: Vec<String>,
+	#[serde(alias="path-is-directory", default="service::xvec")]
+	path_is_directory: Vec<String>,
+	#[serde(alias="file-not-empty", default="service::xvec")]
+	file_not_empty: Vec<String>,
+	#[serde(alias="kernel-cmdline")]
+	kernel_cmdline: Option<String>,
+	virtualization: Option<String>,
+	architecture: Option<String>,
+	#[serde(alias="first-boot")]
+	first_boot: Option<bool>,
+}
+
+/// Split the "!" prefix of a check.
+fn negated(value: &str) -> (&str, bool) {
+	match value.strip_prefix('!') {
+		Some(value) => (value, true),
+		None => (value, false),
+	}
+}
+
+/// The word is on the kernel command line, either alone or as "word=value".
+fn kernel_cmdline(word: &str) -> bool {
+	let cmdline = fs::read_to_string("/proc/cmdline").unwrap_or_default();
+	cmdline.split_whitespace().any(|arg| {
+		arg == word || (! word.contains('=') && arg.split_once('=').is_some_and(|(key, _)| key == word))
+	})
+}
+
+/// Kind of virtualization, "vm" or "container", and its name if known.
+fn virtualization() -> Option<(&'static str, Option<String>)> {
+	for (file, name) in [("/.dockerenv", "docker"), ("/run/.containerenv", "podman")] {
+		if Path::new(file).exists() {
+			return Some(("container", Some(name.into())));
+		}
+	}
+
+	let container = fs::read_to_string("/run/systemd/container").ok()
+		.or_else(|| {
+			let environ = fs::read("/proc/1/environ").ok()?;
+			let var = environ.split(|byte| *byte == 0).find_map(|var| var.strip_prefix(b"container="))?;
+			Some(String::from_utf8_lossy(var).into())
+		});
+	if let Some(container) = container {
+		return Some(("container", Some(container.trim().into())));
+	}
+
+	let vendor = fs::read_to_string("/sys/class/dmi/id/sys_vendor").unwrap_or_default();
+	let product = fs::read_to_string("/sys/class/dmi/id/product_name").unwrap_or_default();
+	for (pattern, name) in [
+		("QEMU", "qemu"), ("KVM", "kvm"), ("VMware", "vmware"), ("VirtualBox", "oracle"),
+		("innotek", "oracle"), ("Xen", "xen"), ("Microsoft", "microsoft"), ("Amazon EC2", "amazon"),
+	] {
+		if vendor.contains(pattern) || product.contains(pattern) {
+			return Some(("vm", Some(name.into())));
+		}
+	}
+
+	let cpuinfo = fs::read_to_string("/proc/cpuinfo").unwrap_or_default();
+	let hypervisor = cpuinfo.lines()
+		.filter(|line| line.starts_with("flags"))
+		.any(|line| line.split_whitespace().any(|flag| flag == "hypervisor"));
+
+	hypervisor.then_some(("vm", None))
+}
+
+fn machine() -> String {
+	unsafe {
+		let mut uname: libc::utsname = std::mem::zeroed();
+		libc::uname(&mut uname);
+		CStr::from_ptr(uname.machine.as_ptr()).to_string_lossy().into()
+	}
+}
+
+/// The system boots for the first time, before its machine id is set.
+fn first_boot() -> bool {
+	let machine_id = fs::read_to_string("/etc/machine-id").unwrap_or_default();
+	matches!(machine_id.trim(), "" | "uninitialized")
+}
+
+impl Conditions {
+	/// The checks, like "path-exists /run/foo".
+	pub fn describe(&self) -> Vec<String> {
+		let mut checks = vec![];
+
+		for (key, paths) in [
+			("path-exists", &self.path_exists),
+			("path-is-directory", &self.path_is_directory),
+			("file-not-empty", &self.file_not_empty),
+		] {
+			checks.extend(paths.iter().map(|path| format!("{key} {path}")));
+		}
+
+		for (key, value) in [
+			("kernel-cmdline", &self.kernel_cmdline),
+			("virtualization", &self.virtualization),
+			("architecture", &self.architecture),
+		] {
+			checks.extend(value.iter().map(|value| format!("{key} {value}")));
+		}
+
+		if let Some(first_boot) = self.first_boot {
+			checks.push(format!("first-boot {first_boot}"));
+		}
+
+		checks
+	}
+
+	/// The first check that doesn't hold.
+	pub fn check(&self) -> Result<(), String> {
+		let paths = [
+			("path-exists", &self.path_exists, Path::exists as fn(&Path) -> bool),
+			("path-is-directory", &self.path_is_directory, Path::is_dir),
+			("file-not-empty", &self.file_not_empty, |path: &Path| {
+				fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.len() > 0)
+			}),
+		];
+
+		for (key, values, holds) in paths {
+			for value in values {
+				let (path, negated) = negated(value);
+				if holds(Path::new(path)) == negated {
+					return Err(format!("{key} {value}"));
+				}
+			}
+		}
+
+		if let Some(value) = &self.kernel_cmdline {
+			let (word, negated) = negated(value);
+			if kernel_cmdline(word) == negated {
+				return Err(format!("kernel-cmdline {value}"));
+			}
+		}
+
+		if let Some(value) = &self.virtualization {
+			let (expected, negated) = negated(value);
+			let holds = match (expected, virtualization()) {
+				("none" | "no" | "false", virtualization) => virtualization.is_none(),
+				("yes" | "true", virtualization) => virtualization.is_some(),
+				(_, None) => false,
+				(expected, Some((kind, name))) => expected == kind || name.is_some_and(|name| name == expected),
+			};
+			if holds == negated {
+				return Err(format!("virtualization {value}"));
+			}
+		}
+
+		if let Some(value) = &self.architecture {
+			let (expected, negated) = negated(value);
+			if (machine() == expected) == negated {
+				return Err(format!("architecture {value}"));
+			}
+		}
+
+		if let Some(expected) = self.first_boot {
+			if first_boot() != expected {
+				return Err(format!("first-boot {expected}"));
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Problems that would make the checks meaningless.
+	pub fn problems(&self, section: &'static str) -> Vec<(&'static str, String)> {
+		let mut problems = vec![];
+
+		let paths = self.path_exists.iter().chain(&self.path_is_directory).chain(&self.file_not_empty);
+		for path in paths {
+			let (path, _) = negated(path);
+			if ! path.starts_with('/') {
+				problems.push((section, format!("path `{path}` is not absolute")));
+			}
+		}
+
+		problems
+	}
+}
@@ -0,0 +1,135 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::service;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+/// Dependencies between services, as declared in their files.
+pub struct Graph {
+	depends: BTreeMap<String, Vec<String>>,
+	problems: BTreeMap<String, &'static str>,
+}
+
+impl Graph {
+	/// Graph of the services and of everything they depend on.
+	pub fn new(service_names: &[String]) -> Graph {
+		let mut depends = BTreeMap::new();
+		let mut problems = BTreeMap::new();
+		let mut queue = service_names.to_vec();
+
+		while let Some(service_name) = queue.pop() {
+			if depends.contains_key(&service_name) {
+				continue;
+			}
+
+			let service = service::load(&service_name);
+
+			if ! Path::new(&service::service_file(&service_name)).exists() {
+				problems.insert(service_name.clone(), "missing");
+			} else if service::masked(&service_name) {
+				problems.insert(service_name.clone(), "masked");
+			} else if service.is_err() {
+				problems.insert(service_name.clone(), "invalid");
+			}
+
+			let service_depends = service.map(|service| service.control.depends).unwrap_or_default();
+			queue.extend(service_depends.iter().cloned());
+			depends.insert(service_name, service_depends);
+		}
+
+		Graph { depends, problems }
+	}
+
+	pub fn depends(&self, service_name: &str) -> Vec<String> {
+		self.depends.get(service_name).cloned().unwrap_or_default()
+	}
+
+	/// Services of the graph depending on the service.
+	pub fn reverse(&self, service_name: &str) -> Vec<String> {
+		self.depends.iter()
+			.filter(|(_, depends)| depends.iter().any(|depend| depend == service_name))
+			.map(|(name, _)| name.clone())
+			.collect()
+	}
+
+	/// Why the service can't be started, if it can't.
+	pub fn problem(&self, service_name: &str) -> Option<&'static str> {
+		self.problems.get(service_name).copied()
+	}
+
+	/// Dependency edges, from the dependent service.
+	pub fn edges(&self) -> Vec<(&str, &str)> {
+		self.depends.iter()
+			.flat_map(|(name, depends)| depends.iter().map(move |depend| (name.as_str(), depend.as_str())))
+			.collect()
+	}
+
+	/// Split the services in levels, each level only depends on the previous ones.
+	///
+	/// Dependencies outside of the services are ignored. Services that are part of
+	/// a cycle, or depend on one, can't be ordered and are returned apart.
+	pub fn levels(&self, service_names: &[String]) -> (Vec<Vec<String>>, Vec<String>) {
+		let mut remaining: BTreeMap<&str, Vec<&str>> = service_names.iter()
+			.map(|name| {
+				let depends = self.depends.get(name).map(|depends| depends.as_slice()).unwrap_or(&[]);
+				let depends = depends.iter().filter(|depend| service_names.contains(depend));
+				(name.as_str(), depends.map(|depend| depend.as_str()).collect())
+			})
+			.collect();
+
+		let mut placed = BTreeSet::new();
+		let mut levels = vec![];
+
+		loop {
+			let level: Vec<&str> = remaining.iter()
+				.filter(|(_, depends)| depends.iter().all(|depend| placed.contains(depend)))
+				.map(|(name, _)| *name)
+				.collect();
+
+			if level.is_empty() {
+				break;
+			}
+
+			for name in &level {
+				remaining.remove(name);
+				placed.insert(*name);
+			}
+
+			levels.push(level.into_iter().map(String::from).collect());
+		}
+
+		(levels, remaining.into_keys().map(String::from).collect())
+	}
+
+	/// Edges closing a cycle, found by walking the graph depth first.
+	pub fn cycles(&self) -> BTreeSet<(String, String)> {
+		fn walk(graph: &Graph, name: &str, path: &mut Vec<String>, done: &mut BTreeSet<String>,
+			cycles: &mut BTreeSet<(String, String)>) {
+			if done.contains(name) {
+				return;
+			}
+
+			path.push(name.into());
+			for depend in graph.depends(name) {
+				if path.contains(&depend) {
+					cycles.insert((name.into(), depend));
+				} else {
+					walk(graph, &depend, path, done, cycles);
+				}
+			}
+			path.pop();
+			done.insert(name.into());
+		}
+
+		let mut cycles = BTreeSet::new();
+		let mut done = BTreeSet::new();
+
+		for name in self.depends.keys() {
+			walk(self, name, &mut vec![], &mut done, &mut cycles);
+		}
+
+		cycles
+	}
+}
@@ -0,0 +1,126 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::{command, service, timer};
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Health {
+	pub cmd: Vec<String>,
+	#[serde(default="interval", deserialize_with="timer::seconds")]
+	interval: Option<u64>,
+	#[serde(default="interval", deserialize_with="timer::seconds")]
+	pub timeout: Option<u64>,
+	#[serde(default="retries")]
+	retries: u32,
+	#[serde(alias="start-period", default, deserialize_with="timer::seconds")]
+	start_period: Option<u64>,
+	#[serde(alias="on-unhealthy", default)]
+	on_unhealthy: OnUnhealthy,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all="lowercase")]
+enum OnUnhealthy {
+	#[default]
+	Restart,
+	None,
+}
+
+fn interval() -> Option<u64> {
+	Some(30)
+}
+
+fn retries() -> u32 {
+	3
+}
+
+impl Health {
+	/// Details shown by `control status --verbose`.
+	pub fn properties(&self) -> Vec<(&'static str, String)> {
+		let interval = timer::format_span(self.interval.unwrap_or(30));
+		let mut properties = vec![("Health command", format!("{} (every {interval})", self.cmd.join(" ")))];
+
+		if self.on_unhealthy == OnUnhealthy::Restart {
+			properties.push(("On unhealthy", format!("restart after {} failures", self.retries)));
+		}
+
+		properties
+	}
+}
+
+/// State of the last checks of the service: "starting", "healthy" or "unhealthy".
+pub fn state(service_name: &str) -> Option<String> {
+	let state = fs::read_to_string(service::healthfile(service_name)).ok()?;
+	Some(state.trim_end().into())
+}
+
+fn state_put(service_name: &str, state: &str) {
+	let _ = fs::write(service::healthfile(service_name), format!("{state}\n"));
+}
+
+/// Check the health of the service process while it runs.
+///
+/// Failures during the start period don't count. Once the checks failed
+/// retries times in a row, the service is unhealthy and restarted if asked to.
+pub fn monitor(service_name: &str, pid: i32, children: &Arc<Mutex<command::Children>>) {
+	let service_name = String::from(service_name);
+	let children = Arc::clone(children);
+
+	thread::spawn(move || {
+		let Ok(service) = service::load(&service_name) else {
+			return;
+		};
+		let Some(health) = &service.health else {
+			return;
+		};
+
+		let started = Instant::now();
+		let start_period = Duration::from_secs(health.start_period.unwrap_or(0));
+		let interval = Duration::from_secs(health.interval.unwrap_or(30).max(1));
+		let alive = || children.lock().unwrap().contains_key(&pid);
+		let mut failures = 0;
+
+		state_put(&service_name, "starting");
+
+		loop {
+			thread::sleep(interval);
+
+			if ! alive() {
+				break;
+			}
+
+			if service.check_health(pid) {
+				failures = 0;
+				state_put(&service_name, "healthy");
+				continue;
+			}
+
+			if started.elapsed() < start_period {
+				continue;
+			}
+
+			failures += 1;
+			if failures < health.retries.max(1) {
+				continue;
+			}
+
+			state_put(&service_name, "unhealthy");
+
+			if health.on_unhealthy == OnUnhealthy::Restart {
+				// The supervisor starts it again once it exited.
+				if let Some(child) = children.lock().unwrap().get_mut(&pid) {
+					child.unhealthy = true;
+				}
+				let _ = service::stop(&service, Some(pid));
+				break;
+			}
+		}
+	});
+}
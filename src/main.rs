@@ -2,15 +2,33 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+mod activation;
 mod command;
+mod condition;
+mod graph;
+mod health;
+mod notify;
+mod sandbox;
+mod seccomp;
 mod service;
 mod socket;
 mod table;
+mod timer;
+mod timing;
+mod validate;
+mod watch;
 use argh::FromArgs;
+use std::process::ExitCode;
 
 #[derive(FromArgs, Debug)]
 /// Control - service supervisor.
 pub struct Args {
+	/// output format: table (default), plain or json
+	#[argh(option, default="table::Output::Table")]
+	output: table::Output,
+	/// colour the output: auto (default), always or never
+	#[argh(option, default="table::Color::Auto")]
+	color: table::Color,
 	/// command
 	#[argh(subcommand)]
 	command: Option<Command>,
@@ -28,8 +46,16 @@ enum Command {
 	Reload(Reload),
 	Enable(Enable),
 	Disable(Disable),
+	Mask(Mask),
+	Unmask(Unmask),
 	Status(Status),
 	Check(Check),
+	Cat(Cat),
+	Deps(Deps),
+	Order(Order),
+	Blame(Blame),
+	CriticalChain(CriticalChain),
+	Timers(Timers),
 }
 
 #[derive(FromArgs, Debug)]
@@ -101,6 +127,24 @@ struct Disable {
 	service_names: Vec<String>,
 }
 
+#[derive(FromArgs, Debug)]
+/// Mask service(s), so they can't be started.
+#[argh(subcommand, name="mask")]
+struct Mask {
+	#[argh(positional)]
+	/// service name
+	service_names: Vec<String>,
+}
+
+#[derive(FromArgs, Debug)]
+/// Unmask service(s).
+#[argh(subcommand, name="unmask")]
+struct Unmask {
+	#[argh(positional)]
+	/// service name
+	service_names: Vec<String>,
+}
+
 #[derive(FromArgs, Debug)]
 /// Show services status.
 #[argh(subcommand, name="status")]
@@ -108,6 +152,9 @@ struct Status {
 	#[argh(positional)]
 	/// service name
 	service_name: Option<String>,
+	#[argh(switch, short='v')]
+	/// show service details
+	verbose: bool,
 }
 
 #[derive(FromArgs, Debug)]
@@ -119,8 +166,67 @@ struct Check {
 	service_name: Option<String>,
 }
 
-fn main() {
+#[derive(FromArgs, Debug)]
+/// Show the service file merged with its drop-ins.
+#[argh(subcommand, name="cat")]
+struct Cat {
+	#[argh(positional)]
+	/// service name
+	service_name: String,
+}
+
+#[derive(FromArgs, Debug)]
+/// Show the dependencies of a service.
+#[argh(subcommand, name="deps")]
+struct Deps {
+	#[argh(positional)]
+	/// service name
+	service_name: String,
+	#[argh(switch, short='r')]
+	/// show the services depending on it instead
+	reverse: bool,
+	#[argh(switch)]
+	/// output a graphviz DOT graph
+	dot: bool,
+}
+
+#[derive(FromArgs, Debug)]
+/// Show the start order of the enabled services.
+#[argh(subcommand, name="order")]
+struct Order {
+	#[argh(switch)]
+	/// output a graphviz DOT graph
+	dot: bool,
+}
+
+#[derive(FromArgs, Debug)]
+/// Show the services sorted by startup duration.
+#[argh(subcommand, name="blame")]
+struct Blame {
+	#[argh(switch)]
+	/// output an SVG timeline
+	svg: bool,
+}
+
+#[derive(FromArgs, Debug)]
+/// Show the dependency path that delayed the service.
+#[argh(subcommand, name="critical-chain")]
+struct CriticalChain {
+	#[argh(positional)]
+	/// service name
+	service_name: Option<String>,
+}
+
+#[derive(FromArgs, Debug)]
+/// Show the timers, with their next and last run.
+#[argh(subcommand, name="timers")]
+struct Timers {}
+
+fn main() -> ExitCode {
 	let args: Args = argh::from_env();
+	table::set_output(args.output);
+	table::set_color(args.color);
+
 	match args.command {
 		Some(Command::StartAll(_)) => command::start_all(),
 		Some(Command::StopAll(_)) => command::stop_all(),
@@ -131,8 +237,16 @@ fn main() {
 		Some(Command::Reload(args1)) => command::reload(args1.service_names),
 		Some(Command::Enable(args1)) => command::enable(args1.service_names),
 		Some(Command::Disable(args1)) => command::disable(args1.service_names),
-		Some(Command::Status(args1)) => command::status(args1.service_name),
+		Some(Command::Mask(args1)) => command::mask(args1.service_names),
+		Some(Command::Unmask(args1)) => command::unmask(args1.service_names),
+		Some(Command::Status(args1)) => command::status(args1.service_name, args1.verbose),
 		Some(Command::Check(args1)) => command::check(args1.service_name),
-		None => command::status(None),
+		Some(Command::Cat(args1)) => command::cat(args1.service_name),
+		Some(Command::Deps(args1)) => command::deps(args1.service_name, args1.reverse, args1.dot),
+		Some(Command::Order(args1)) => command::order(args1.dot),
+		Some(Command::Blame(args1)) => command::blame(args1.svg),
+		Some(Command::CriticalChain(args1)) => command::critical_chain(args1.service_name),
+		Some(Command::Timers(_)) => command::timers(),
+		None => command::status(None, false),
 	}
 }
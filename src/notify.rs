@@ -0,0 +1,175 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::{command, service, table, timing};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Last watchdog ping of the supervised processes.
+static PINGS: Mutex<BTreeMap<i32, Instant>> = Mutex::new(BTreeMap::new());
+
+/// Time given to a process to dump its core before it is killed.
+const ABORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The supervised process the sender belongs to, itself or one of its ancestors.
+fn supervised(pid: i32, children: &command::Children) -> Option<i32> {
+	let mut pid = pid;
+
+	while pid > 1 {
+		if children.contains_key(&pid) {
+			return Some(pid);
+		}
+
+		// The parent PID follows the command name, which is in parentheses.
+		let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+		let (_, stat) = stat.rsplit_once(')')?;
+		pid = stat.split_whitespace().nth(1)?.parse().ok()?;
+	}
+
+	None
+}
+
+/// Receive a message along with the PID of its sender.
+fn receive(sock: &UnixDatagram) -> io::Result<(String, i32)> {
+	let mut buffer = [0u8; 4096];
+	let mut control = [0u8; 64];
+	let mut iov = libc::iovec { iov_base: buffer.as_mut_ptr() as *mut libc::c_void, iov_len: buffer.len() };
+
+	unsafe {
+		let mut msg: libc::msghdr = std::mem::zeroed();
+		msg.msg_iov = &mut iov;
+		msg.msg_iovlen = 1;
+		msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+		msg.msg_controllen = control.len() as _;
+
+		let len = libc::recvmsg(sock.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+		if len < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		let mut pid = 0;
+		let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+		while ! cmsg.is_null() {
+			if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_CREDENTIALS {
+				let ucred = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::ucred);
+				pid = ucred.pid;
+			}
+			cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+		}
+
+		Ok((String::from_utf8_lossy(&buffer[.. len as usize]).into(), pid))
+	}
+}
+
+/// Bind the notify socket, the services send their watchdog pings on it.
+pub fn listen(children: &Arc<Mutex<command::Children>>) {
+	let path = service::notify_sock();
+	fs::remove_file(&path).ok();
+
+	let sock = match UnixDatagram::bind(&path) {
+		Ok(sock) => sock,
+		Err(err) => return table::log_bold("Cannot bind", &format!("{path} ({err})")),
+	};
+
+	// Services running as other users ping too.
+	let _ = fs::set_permissions(&path, fs::Permissions::from_mode(0o777));
+
+	unsafe {
+		let on: libc::c_int = 1;
+		libc::setsockopt(sock.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PASSCRED,
+			&on as *const libc::c_int as *const libc::c_void, std::mem::size_of::<libc::c_int>() as u32);
+	}
+
+	let children = Arc::clone(children);
+
+	thread::spawn(move || {
+		let mut ready = BTreeSet::new();
+
+		loop {
+			let Ok((message, pid)) = receive(&sock) else {
+				continue;
+			};
+
+			let watchdog = message.lines().any(|line| line == "WATCHDOG=1");
+			let readiness = message.lines().any(|line| line == "READY=1");
+			if ! watchdog && ! readiness {
+				continue;
+			}
+
+			// Only the process itself pings, unless the service opened access to all of its processes.
+			let children = children.lock().unwrap();
+			let Some(main) = supervised(pid, &children) else {
+				continue;
+			};
+			if main != pid && ! children[&main].notify_all {
+				continue;
+			}
+			let service_name = children[&main].service_name.clone();
+			drop(children);
+
+			// The boot timings keep the first readiness of the service.
+			if readiness && ready.insert(main) {
+				timing::record("ready", &service_name);
+			}
+
+			if watchdog {
+				PINGS.lock().unwrap().insert(main, Instant::now());
+			}
+		}
+	});
+}
+
+/// Kill the service process once it misses its watchdog pings.
+///
+/// It is sent SIGABRT first to dump its core, then SIGKILL, and the
+/// supervisor starts it again once it exited.
+pub fn watchdog(service_name: &str, pid: i32, timeout: u64, children: &Arc<Mutex<command::Children>>) {
+	let service_name = String::from(service_name);
+	let children = Arc::clone(children);
+	let timeout = Duration::from_secs(timeout.max(1));
+
+	thread::spawn(move || {
+		let started = Instant::now();
+		let alive = || children.lock().unwrap().contains_key(&pid);
+
+		loop {
+			thread::sleep(Duration::from_millis(500));
+
+			if ! alive() {
+				break;
+			}
+
+			let last = PINGS.lock().unwrap().get(&pid).copied().unwrap_or(started);
+
+			if last.elapsed() < timeout {
+				continue;
+			}
+
+			table::log_bold("Watchdog timeout", &service_name);
+
+			if let Some(child) = children.lock().unwrap().get_mut(&pid) {
+				child.unhealthy = true;
+			}
+
+			unsafe { libc::kill(pid, libc::SIGABRT) };
+			let aborted = Instant::now();
+			while alive() && aborted.elapsed() < ABORT_TIMEOUT {
+				thread::sleep(Duration::from_millis(100));
+			}
+			if alive() {
+				unsafe { libc::kill(pid, libc::SIGKILL) };
+			}
+			break;
+		}
+
+		PINGS.lock().unwrap().remove(&pid);
+	});
+}
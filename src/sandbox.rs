@@ -0,0 +1,714 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::{seccomp, service};
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::atomic::{AtomicI32, Ordering};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Sandbox {
+	#[serde(alias="private-tmp", default="service::xfalse")]
+	private_tmp: bool,
+	#[serde(alias="protect-system")]
+	protect_system: Option<ProtectSystem>,
+	#[serde(alias="protect-home")]
+	protect_home: Option<ProtectHome>,
+	#[serde(alias="read-only-paths", default="service::xvec")]
+	read_only_paths: Vec<String>,
+	#[serde(alias="read-write-paths", default="service::xvec")]
+	read_write_paths: Vec<String>,
+	#[serde(alias="inaccessible-paths", default="service::xvec")]
+	inaccessible_paths: Vec<String>,
+	#[serde(alias="bind-paths", default="service::xvec")]
+	bind_paths: Vec<String>,
+	#[serde(alias="private-network", default="service::xfalse")]
+	private_network: bool,
+	#[serde(alias="private-pids", default="service::xfalse")]
+	private_pids: bool,
+	#[serde(alias="private-ipc", default="service::xfalse")]
+	private_ipc: bool,
+	hostname: Option<String>,
+	#[serde(alias="syscall-filter", default="service::xvec", deserialize_with="seccomp::filter")]
+	syscall_filter: Vec<String>,
+	#[serde(alias="syscall-action", default)]
+	syscall_action: seccomp::Action,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="kebab-case")]
+enum ProtectSystem {
+	Yes,
+	Full,
+	Strict,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="kebab-case")]
+enum ProtectHome {
+	Yes,
+	ReadOnly,
+	Tmpfs,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all="kebab-case")]
+pub enum IoClass {
+	Realtime,
+	BestEffort,
+	Idle,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all="kebab-case")]
+pub enum SchedulingPolicy {
+	Other,
+	Batch,
+	Idle,
+	Fifo,
+	Rr,
+}
+
+impl IoClass {
+	pub fn name(&self) -> &'static str {
+		match self {
+			IoClass::Realtime => "realtime",
+			IoClass::BestEffort => "best-effort",
+			IoClass::Idle => "idle",
+		}
+	}
+}
+
+impl SchedulingPolicy {
+	pub fn name(&self) -> &'static str {
+		match self {
+			SchedulingPolicy::Other => "other",
+			SchedulingPolicy::Batch => "batch",
+			SchedulingPolicy::Idle => "idle",
+			SchedulingPolicy::Fifo => "fifo",
+			SchedulingPolicy::Rr => "rr",
+		}
+	}
+}
+
+enum Mount {
+	Bind(CString, CString),
+	ReadOnly(CString),
+	ReadWrite(CString),
+	Tmpfs(CString, CString, libc::c_ulong),
+	Inaccessible(CString),
+	Proc(CString),
+}
+
+/// Everything the child has to do between fork and exec.
+///
+/// All strings are prepared by the parent, so `enter` only calls into libc.
+#[derive(Default)]
+pub struct Jail {
+	namespaces: libc::c_int,
+	hostname: Option<CString>,
+	mounts: Vec<Mount>,
+	root: Option<String>,
+	pivot: Option<CString>,
+	workdir: Option<CString>,
+	uid: Option<libc::uid_t>,
+	gid: Option<libc::gid_t>,
+	seccomp: Option<Vec<libc::sock_filter>>,
+	umask: Option<libc::mode_t>,
+	nice: Option<libc::c_int>,
+	io_priority: Option<libc::c_int>,
+	cpu_affinity: Option<libc::cpu_set_t>,
+	oom_score_adjust: Option<CString>,
+	scheduling: Option<(libc::c_int, libc::c_int)>,
+}
+
+fn cstring(path: &str) -> CString {
+	CString::new(path).unwrap()
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<()> {
+	if ret < 0 {
+		Err(io::Error::last_os_error())
+	} else {
+		Ok(())
+	}
+}
+
+/// The process signals are relayed to, once it is forked.
+static FORWARD_PID: AtomicI32 = AtomicI32::new(0);
+
+/// Signals relayed to the service, through the init of its PID namespace.
+const FORWARDED: [libc::c_int; 8] = [libc::SIGHUP, libc::SIGINT, libc::SIGQUIT, libc::SIGTERM,
+	libc::SIGUSR1, libc::SIGUSR2, libc::SIGWINCH, libc::SIGABRT];
+
+extern "C" fn on_forward(signal: libc::c_int) {
+	let pid = FORWARD_PID.load(Ordering::Relaxed);
+	if pid > 0 {
+		unsafe { libc::kill(pid, signal); }
+	}
+}
+
+fn mountpoints() -> Vec<String> {
+	let mounts = fs::read_to_string("/proc/self/mounts").unwrap_or_default();
+	mounts.lines().filter_map(|line| line.split(' ').nth(1))
+		.map(|target| target.replace("\\040", " ").replace("\\011", "\t")
+			.replace("\\012", "\n").replace("\\134", "\\"))
+		.collect()
+}
+
+/// Strip the optional "-" prefix, which means that a missing path is not an error.
+fn optional(path: &str) -> (&str, bool) {
+	match path.strip_prefix('-') {
+		Some(path) => (path, true),
+		None => (path, false),
+	}
+}
+
+/// Files bind mounted into the root directory by `root-binds`, onto existing files.
+pub const ROOT_BINDS: [&str; 3] = ["/dev/null", "/dev/urandom", "/etc/resolv.conf"];
+
+fn missing(path: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::NotFound, format!("{path}: No such file or directory"))
+}
+
+/// Translate a path inside the service's root directory to a path in ours.
+fn inside(root: &Option<String>, path: &str) -> String {
+	match root {
+		Some(root) if path == "/" => root.clone(),
+		Some(root) => format!("{root}{path}"),
+		None => path.into(),
+	}
+}
+
+impl Jail {
+	pub fn new() -> Jail {
+		Jail::default()
+	}
+
+	pub fn uid(&mut self, uid: libc::uid_t) -> &mut Jail {
+		self.uid = Some(uid);
+		self
+	}
+
+	pub fn gid(&mut self, gid: libc::gid_t) -> &mut Jail {
+		self.gid = Some(gid);
+		self
+	}
+
+	pub fn workdir(&mut self, workdir: &str) -> &mut Jail {
+		self.workdir = Some(cstring(workdir));
+		self
+	}
+
+	pub fn umask(&mut self, umask: libc::mode_t) -> &mut Jail {
+		self.umask = Some(umask);
+		self
+	}
+
+	pub fn nice(&mut self, nice: i32) -> &mut Jail {
+		self.nice = Some(nice);
+		self
+	}
+
+	pub fn io_priority(&mut self, class: IoClass, priority: Option<i32>) -> &mut Jail {
+		let (class, priority) = match class {
+			IoClass::Realtime => (1, priority.unwrap_or(4)),
+			IoClass::BestEffort => (2, priority.unwrap_or(4)),
+			IoClass::Idle => (3, 0),
+		};
+		self.io_priority = Some(class << 13 | priority);
+		self
+	}
+
+	pub fn cpu_affinity(&mut self, cpus: &[usize]) -> &mut Jail {
+		unsafe {
+			let mut set: libc::cpu_set_t = std::mem::zeroed();
+			for cpu in cpus {
+				libc::CPU_SET(*cpu, &mut set);
+			}
+			self.cpu_affinity = Some(set);
+		}
+		self
+	}
+
+	pub fn oom_score_adjust(&mut self, oom_score_adjust: i32) -> &mut Jail {
+		self.oom_score_adjust = Some(cstring(&oom_score_adjust.to_string()));
+		self
+	}
+
+	pub fn scheduling(&mut self, policy: SchedulingPolicy, priority: Option<i32>) -> &mut Jail {
+		let policy = match policy {
+			SchedulingPolicy::Other => (libc::SCHED_OTHER, 0),
+			SchedulingPolicy::Batch => (libc::SCHED_BATCH, 0),
+			SchedulingPolicy::Idle => (libc::SCHED_IDLE, 0),
+			SchedulingPolicy::Fifo => (libc::SCHED_FIFO, priority.unwrap_or(1)),
+			SchedulingPolicy::Rr => (libc::SCHED_RR, priority.unwrap_or(1)),
+		};
+		self.scheduling = Some(policy);
+		self
+	}
+
+	/// Bind mount the root directory onto itself, so it can become the new root.
+	pub fn root_dir(&mut self, root_dir: &str, binds: bool) -> io::Result<&mut Jail> {
+		let root_dir = root_dir.trim_end_matches('/');
+		if ! Path::new(root_dir).is_dir() {
+			return Err(missing(root_dir));
+		}
+
+		self.mounts.push(Mount::Bind(cstring(root_dir), cstring(root_dir)));
+		self.root = Some(root_dir.into());
+		self.pivot = Some(cstring(root_dir));
+
+		if binds {
+			for file in ROOT_BINDS {
+				if ! Path::new(file).exists() {
+					continue;
+				}
+				let dst = self.inside(file);
+				if ! Path::new(&dst).exists() {
+					return Err(missing(&dst));
+				}
+				self.mounts.push(Mount::Bind(cstring(file), cstring(&dst)));
+				if file.starts_with("/etc/") {
+					self.mounts.push(Mount::ReadOnly(cstring(&dst)));
+				}
+			}
+		}
+
+		Ok(self)
+	}
+
+	fn inside(&self, path: &str) -> String {
+		inside(&self.root, path)
+	}
+
+	fn read_only(&mut self, path: &str, mountpoints: &[String], except: &[&str]) {
+		let under = |target: &str, dir: &str| {
+			dir == "/" || target == dir || target.starts_with(&format!("{dir}/"))
+		};
+
+		let path = &self.inside(path);
+		let except: Vec<_> = except.iter().map(|dir| self.inside(dir)).collect();
+
+		self.mounts.push(Mount::ReadOnly(cstring(path)));
+		for target in mountpoints {
+			if target != path && under(target, path)
+				&& ! except.iter().any(|dir| under(target, dir)) {
+				self.mounts.push(Mount::ReadOnly(cstring(target)));
+			}
+		}
+	}
+
+	fn inaccessible(&mut self, path: &str) {
+		let path = &self.inside(path);
+		if Path::new(path).is_dir() {
+			let data = cstring("mode=000");
+			let flags = libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+			self.mounts.push(Mount::Tmpfs(cstring(path), data, flags));
+		} else {
+			self.mounts.push(Mount::Inaccessible(cstring(path)));
+		}
+	}
+
+	pub fn sandbox(&mut self, sandbox: &Sandbox) -> io::Result<&mut Jail> {
+		let mountpoints = mountpoints();
+		let root = self.root.clone();
+		let exists = |path: &str| Path::new(&inside(&root, path)).exists();
+
+		for bind in &sandbox.bind_paths {
+			let (bind, optional) = optional(bind);
+			let (src, dst) = bind.split_once(':').unwrap_or((bind, bind));
+			if ! Path::new(src).exists() || ! exists(dst) {
+				if optional { continue; }
+				return Err(missing(if Path::new(src).exists() { dst } else { src }));
+			}
+			self.mounts.push(Mount::Bind(cstring(src), cstring(&self.inside(dst))));
+		}
+
+		if sandbox.private_tmp {
+			let flags = libc::MS_NOSUID | libc::MS_NODEV;
+			for tmp in ["/tmp", "/var/tmp"].into_iter().filter(|tmp| exists(tmp)) {
+				let tmp = cstring(&self.inside(tmp));
+				self.mounts.push(Mount::Tmpfs(tmp, cstring("mode=1777"), flags));
+			}
+		}
+
+		match sandbox.protect_system {
+			Some(ProtectSystem::Strict) => {
+				self.read_only("/", &mountpoints, &["/dev", "/proc", "/sys"]);
+			},
+			Some(ref protect) => {
+				let mut paths = vec!["/usr", "/boot", "/efi"];
+				if let ProtectSystem::Full = protect {
+					paths.push("/etc");
+				}
+				for path in paths.into_iter().filter(|path| exists(path)) {
+					self.read_only(path, &mountpoints, &[]);
+				}
+			},
+			None => {},
+		}
+
+		if let Some(protect) = &sandbox.protect_home {
+			for home in ["/home", "/root", "/run/user"].into_iter().filter(|home| exists(home)) {
+				match protect {
+					ProtectHome::Yes => self.inaccessible(home),
+					ProtectHome::ReadOnly => self.read_only(home, &mountpoints, &[]),
+					ProtectHome::Tmpfs => {
+						let flags = libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV;
+						let home = cstring(&self.inside(home));
+						let mount = Mount::Tmpfs(home, cstring("mode=755"), flags);
+						self.mounts.push(mount);
+					},
+				}
+			}
+		}
+
+		for path in &sandbox.read_only_paths {
+			let (path, optional) = optional(path);
+			if ! exists(path) {
+				if optional { continue; }
+				return Err(missing(path));
+			}
+			self.read_only(path, &mountpoints, &[]);
+		}
+
+		for path in &sandbox.read_write_paths {
+			let (path, optional) = optional(path);
+			if ! exists(path) {
+				if optional { continue; }
+				return Err(missing(path));
+			}
+			self.mounts.push(Mount::ReadWrite(cstring(&self.inside(path))));
+		}
+
+		for path in &sandbox.inaccessible_paths {
+			let (path, optional) = optional(path);
+			if ! exists(path) {
+				if optional { continue; }
+				return Err(missing(path));
+			}
+			self.inaccessible(path);
+		}
+
+		if sandbox.private_network {
+			self.namespaces |= libc::CLONE_NEWNET;
+		}
+
+		if sandbox.private_ipc {
+			self.namespaces |= libc::CLONE_NEWIPC;
+		}
+
+		if let Some(hostname) = &sandbox.hostname {
+			self.namespaces |= libc::CLONE_NEWUTS;
+			self.hostname = Some(cstring(hostname));
+		}
+
+		if sandbox.private_pids {
+			self.namespaces |= libc::CLONE_NEWPID;
+			self.mounts.push(Mount::Proc(cstring(&self.inside("/proc"))));
+		}
+
+		if ! sandbox.syscall_filter.is_empty() {
+			let filter = seccomp::compile(&sandbox.syscall_filter, sandbox.syscall_action)?;
+			self.seccomp = Some(filter);
+		}
+
+		Ok(self)
+	}
+
+	/// Runs in the child process, right before exec.
+	pub fn enter(&self) -> io::Result<()> {
+		unsafe { self.enter_resources()?; }
+
+		if self.namespaces != 0 {
+			cvt(unsafe { libc::unshare(self.namespaces) })?;
+		}
+
+		if self.namespaces & libc::CLONE_NEWPID != 0 {
+			unsafe { fork_pid_1()?; }
+		}
+
+		if ! self.mounts.is_empty() {
+			unsafe { self.enter_mounts()?; }
+		}
+
+		if let Some(hostname) = &self.hostname {
+			let len = hostname.as_bytes().len();
+			cvt(unsafe { libc::sethostname(hostname.as_ptr(), len) })?;
+		}
+
+		if self.namespaces & libc::CLONE_NEWNET != 0 {
+			unsafe { loopback_up()?; }
+		}
+
+		if let Some(workdir) = &self.workdir {
+			cvt(unsafe { libc::chdir(workdir.as_ptr()) })?;
+		} else if self.pivot.is_some() {
+			cvt(unsafe { libc::chdir(c"/".as_ptr()) })?;
+		}
+
+		unsafe {
+			if let Some(gid) = self.gid {
+				cvt(libc::setgid(gid))?;
+			}
+			if let Some(uid) = self.uid {
+				if libc::getuid() == 0 {
+					cvt(libc::setgroups(0, std::ptr::null()))?;
+				}
+				cvt(libc::setuid(uid))?;
+			}
+
+			if let Some(filter) = &self.seccomp {
+				seccomp::install(filter)?;
+			}
+		}
+
+		Ok(())
+	}
+
+	unsafe fn enter_resources(&self) -> io::Result<()> {
+		if let Some(umask) = self.umask {
+			libc::umask(umask);
+		}
+
+		if let Some(nice) = self.nice {
+			cvt(libc::setpriority(libc::PRIO_PROCESS as _, 0, nice))?;
+		}
+
+		if let Some(io_priority) = self.io_priority {
+			// IOPRIO_WHO_PROCESS of the calling process.
+			cvt(libc::syscall(libc::SYS_ioprio_set, 1, 0, io_priority) as libc::c_int)?;
+		}
+
+		if let Some(set) = &self.cpu_affinity {
+			cvt(libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), set))?;
+		}
+
+		if let Some(oom_score_adjust) = &self.oom_score_adjust {
+			let path = c"/proc/self/oom_score_adj".as_ptr();
+			let fd = libc::open(path, libc::O_WRONLY | libc::O_CLOEXEC);
+			if fd < 0 {
+				return Err(io::Error::last_os_error());
+			}
+			let len = oom_score_adjust.as_bytes().len();
+			let ret = libc::write(fd, oom_score_adjust.as_ptr() as *const libc::c_void, len);
+			let err = io::Error::last_os_error();
+			libc::close(fd);
+			if ret < 0 {
+				return Err(err);
+			}
+		}
+
+		if let Some((policy, priority)) = self.scheduling {
+			let param = libc::sched_param { sched_priority: priority };
+			cvt(libc::sched_setscheduler(0, policy, &param))?;
+		}
+
+		Ok(())
+	}
+
+	unsafe fn enter_mounts(&self) -> io::Result<()> {
+		let null = std::ptr::null();
+		let none = c"none".as_ptr();
+		let tmpfs = c"tmpfs".as_ptr();
+
+		cvt(libc::unshare(libc::CLONE_NEWNS))?;
+		cvt(libc::mount(none, c"/".as_ptr(), null, libc::MS_REC | libc::MS_SLAVE, null as _))?;
+
+		let remount = |path: &CString, rdonly: bool| -> io::Result<()> {
+			let mut stat: libc::statvfs = std::mem::zeroed();
+			cvt(libc::statvfs(path.as_ptr(), &mut stat))?;
+
+			let mut flags = libc::MS_BIND | libc::MS_REMOUNT;
+			for (st, ms) in [
+				(libc::ST_NOSUID, libc::MS_NOSUID),
+				(libc::ST_NODEV, libc::MS_NODEV),
+				(libc::ST_NOEXEC, libc::MS_NOEXEC),
+				(libc::ST_NOATIME, libc::MS_NOATIME),
+				(libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+			] {
+				if stat.f_flag & st != 0 {
+					flags |= ms;
+				}
+			}
+			if rdonly {
+				flags |= libc::MS_RDONLY;
+			}
+
+			cvt(libc::mount(none, path.as_ptr(), null, flags, null as _))
+		};
+
+		let bind = |src: &CStr, dst: &CStr| -> io::Result<()> {
+			let flags = libc::MS_BIND | libc::MS_REC;
+			cvt(libc::mount(src.as_ptr(), dst.as_ptr(), null, flags, null as _))
+		};
+
+		for mount in &self.mounts {
+			match mount {
+				Mount::Bind(src, dst) => bind(src, dst)?,
+				Mount::ReadOnly(path) => {
+					bind(path, path)?;
+					remount(path, true)?;
+				},
+				Mount::ReadWrite(path) => {
+					bind(path, path)?;
+					remount(path, false)?;
+				},
+				Mount::Tmpfs(path, data, flags) => {
+					let data = data.as_ptr() as *const libc::c_void;
+					cvt(libc::mount(tmpfs, path.as_ptr(), tmpfs, *flags, data))?;
+				},
+				Mount::Proc(path) => {
+					let proc = c"proc".as_ptr();
+					let flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+					cvt(libc::mount(proc, path.as_ptr(), proc, flags, null as _))?;
+				},
+				Mount::Inaccessible(path) => {
+					bind(c"/dev/null", path)?;
+					let flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY
+						| libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+					cvt(libc::mount(none, path.as_ptr(), null, flags, null as _))?;
+				},
+			}
+		}
+
+		if let Some(root) = &self.pivot {
+			let dot = c".".as_ptr();
+			cvt(libc::chdir(root.as_ptr()))?;
+			cvt(libc::syscall(libc::SYS_pivot_root, dot, dot) as libc::c_int)?;
+			cvt(libc::umount2(dot, libc::MNT_DETACH))?;
+		}
+
+		Ok(())
+	}
+}
+
+unsafe fn forward(handler: libc::sighandler_t) {
+	for signal in FORWARDED {
+		libc::signal(signal, handler);
+	}
+}
+
+/// Close every file descriptor but the standard ones and the given one.
+///
+/// Neither the exec status pipe nor the sockets of the service are held open,
+/// or spawning would wait for us.
+unsafe fn close_except(fd: libc::c_int) {
+	libc::syscall(libc::SYS_close_range, 3, fd - 1, 0);
+	libc::syscall(libc::SYS_close_range, fd + 1, libc::c_uint::MAX, 0);
+}
+
+/// Fork the processes of the new PID namespace, the last one goes on to exec the service.
+///
+/// The parent stays behind in our namespace, so the supervisor can track it.
+/// Its child is the init of the namespace: the kernel drops the signals sent
+/// from our namespace to a PID 1 without handler, so the service runs as PID 2
+/// under it. Both relay signals down to the service, the init reaps the orphans
+/// and hands the service's wait status up, so the parent exits the same way.
+unsafe fn fork_pid_1() -> io::Result<()> {
+	let mut status = [0; 2];
+	cvt(libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0, status.as_mut_ptr()))?;
+	let [outer, inner] = status;
+
+	let init = libc::fork();
+	if init < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	if init == 0 {
+		libc::close(outer);
+		return fork_pid_2(inner);
+	}
+
+	libc::close(inner);
+	FORWARD_PID.store(init, Ordering::Relaxed);
+	forward(on_forward as extern "C" fn(libc::c_int) as libc::sighandler_t);
+	close_except(outer);
+
+	// The status of the service, or that of the init if it was killed.
+	let mut wstatus: libc::c_int = 0;
+	let len = libc::read(outer, &mut wstatus as *mut libc::c_int as *mut libc::c_void, size_of::<libc::c_int>());
+	let mut init_wstatus: libc::c_int = 0;
+	while libc::waitpid(init, &mut init_wstatus, 0) < 0 {
+		if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+			libc::_exit(1);
+		}
+	}
+	if len != size_of::<libc::c_int>() as isize {
+		wstatus = init_wstatus;
+	}
+
+	if libc::WIFSIGNALED(wstatus) {
+		let signal = libc::WTERMSIG(wstatus);
+		forward(libc::SIG_DFL);
+		libc::signal(signal, libc::SIG_DFL);
+		libc::kill(libc::getpid(), signal);
+		libc::_exit(128 + signal);
+	}
+	libc::_exit(libc::WEXITSTATUS(wstatus));
+}
+
+/// Runs as the init of the PID namespace, the child returns to exec the service.
+unsafe fn fork_pid_2(status: libc::c_int) -> io::Result<()> {
+	// The whole namespace goes away with the init, the init goes away with its parent.
+	cvt(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+	let mut pollfd = libc::pollfd { fd: status, events: 0, revents: 0 };
+	if libc::poll(&mut pollfd, 1, 0) > 0 && pollfd.revents & libc::POLLHUP != 0 {
+		libc::_exit(1);
+	}
+
+	forward(on_forward as extern "C" fn(libc::c_int) as libc::sighandler_t);
+
+	let service = libc::fork();
+	if service < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	if service == 0 {
+		forward(libc::SIG_DFL);
+		return Ok(());
+	}
+
+	FORWARD_PID.store(service, Ordering::Relaxed);
+	close_except(status);
+
+	loop {
+		let mut wstatus: libc::c_int = 0;
+		let pid = libc::waitpid(-1, &mut wstatus, 0);
+
+		if pid == service {
+			libc::write(status, &wstatus as *const libc::c_int as *const libc::c_void, size_of::<libc::c_int>());
+			libc::_exit(0);
+		}
+		if pid < 0 && io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+			libc::_exit(1);
+		}
+	}
+}
+
+unsafe fn loopback_up() -> io::Result<()> {
+	let sock = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+	if sock < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	let mut req: libc::ifreq = std::mem::zeroed();
+	for (i, byte) in b"lo".iter().enumerate() {
+		req.ifr_name[i] = *byte as libc::c_char;
+	}
+
+	let mut ret = libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut req);
+	if ret == 0 {
+		req.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+		ret = libc::ioctl(sock, libc::SIOCSIFFLAGS, &req);
+	}
+
+	let err = io::Error::last_os_error();
+	libc::close(sock);
+	if ret < 0 { Err(err) } else { Ok(()) }
+}
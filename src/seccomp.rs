@@ -0,0 +1,305 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io;
+use serde::{de, Deserialize, Deserializer};
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all="kebab-case")]
+pub enum Action {
+	#[default]
+	Eperm,
+	Kill,
+}
+
+macro_rules! syscalls {
+	($($name:ident)*) => { &[$((stringify!($name), libc::$name)),*] };
+}
+
+const SYSCALLS: &[(&str, libc::c_long)] = syscalls! {
+	SYS_io_setup SYS_io_destroy SYS_io_submit SYS_io_cancel SYS_io_getevents
+	SYS_io_uring_setup SYS_io_uring_enter SYS_io_uring_register SYS_setxattr
+	SYS_lsetxattr SYS_fsetxattr SYS_getxattr SYS_lgetxattr SYS_fgetxattr SYS_listxattr
+	SYS_llistxattr SYS_flistxattr SYS_removexattr SYS_lremovexattr SYS_fremovexattr
+	SYS_getcwd SYS_lookup_dcookie SYS_eventfd2 SYS_epoll_create1 SYS_epoll_ctl
+	SYS_epoll_pwait SYS_epoll_pwait2 SYS_dup SYS_dup3 SYS_fcntl SYS_inotify_init1
+	SYS_inotify_add_watch SYS_inotify_rm_watch SYS_ioctl SYS_ioprio_set SYS_ioprio_get
+	SYS_flock SYS_mknodat SYS_mkdirat SYS_unlinkat SYS_symlinkat SYS_linkat
+	SYS_renameat2 SYS_umount2 SYS_mount SYS_pivot_root SYS_chroot SYS_statfs
+	SYS_fstatfs SYS_truncate SYS_ftruncate SYS_fallocate SYS_faccessat SYS_faccessat2
+	SYS_chdir SYS_fchdir SYS_fchmod SYS_fchmodat SYS_fchown SYS_fchownat SYS_openat
+	SYS_openat2 SYS_close SYS_close_range SYS_vhangup SYS_pipe2 SYS_quotactl
+	SYS_getdents64 SYS_lseek SYS_read SYS_write SYS_readv SYS_writev SYS_pread64
+	SYS_pwrite64 SYS_preadv SYS_pwritev SYS_preadv2 SYS_pwritev2 SYS_pselect6 SYS_ppoll
+	SYS_signalfd4 SYS_vmsplice SYS_splice SYS_tee SYS_readlinkat SYS_fstat
+	SYS_newfstatat SYS_statx SYS_sync SYS_syncfs SYS_fsync SYS_fdatasync
+	SYS_timerfd_create SYS_timerfd_settime SYS_timerfd_gettime SYS_utimensat SYS_acct
+	SYS_capget SYS_capset SYS_personality SYS_exit SYS_exit_group SYS_waitid SYS_wait4
+	SYS_set_tid_address SYS_unshare SYS_setns SYS_futex SYS_set_robust_list
+	SYS_get_robust_list SYS_nanosleep SYS_getitimer SYS_setitimer SYS_kexec_load
+	SYS_init_module SYS_finit_module SYS_delete_module SYS_timer_create
+	SYS_timer_gettime SYS_timer_getoverrun SYS_timer_settime SYS_timer_delete
+	SYS_clock_settime SYS_clock_gettime SYS_clock_getres SYS_clock_nanosleep
+	SYS_clock_adjtime SYS_syslog SYS_ptrace SYS_sched_setparam SYS_sched_setscheduler
+	SYS_sched_getscheduler SYS_sched_getparam SYS_sched_setaffinity
+	SYS_sched_getaffinity SYS_sched_yield SYS_sched_get_priority_max
+	SYS_sched_get_priority_min SYS_sched_rr_get_interval SYS_sched_setattr
+	SYS_sched_getattr SYS_restart_syscall SYS_kill SYS_tkill SYS_tgkill SYS_sigaltstack
+	SYS_rt_sigsuspend SYS_rt_sigaction SYS_rt_sigprocmask SYS_rt_sigpending
+	SYS_rt_sigtimedwait SYS_rt_sigqueueinfo SYS_rt_tgsigqueueinfo SYS_rt_sigreturn
+	SYS_setpriority SYS_getpriority SYS_reboot SYS_setregid SYS_setgid SYS_setreuid
+	SYS_setuid SYS_setresuid SYS_getresuid SYS_setresgid SYS_getresgid SYS_setfsuid
+	SYS_setfsgid SYS_times SYS_setpgid SYS_getpgid SYS_getsid SYS_setsid SYS_getgroups
+	SYS_setgroups SYS_uname SYS_sethostname SYS_setdomainname SYS_prlimit64
+	SYS_getrusage SYS_umask SYS_prctl SYS_getcpu SYS_gettimeofday SYS_settimeofday
+	SYS_adjtimex SYS_getpid SYS_getppid SYS_getuid SYS_geteuid SYS_getgid SYS_getegid
+	SYS_gettid SYS_sysinfo SYS_mq_open SYS_mq_unlink SYS_mq_timedsend
+	SYS_mq_timedreceive SYS_mq_notify SYS_mq_getsetattr SYS_msgget SYS_msgctl
+	SYS_msgrcv SYS_msgsnd SYS_semget SYS_semctl SYS_semtimedop SYS_semop SYS_shmget
+	SYS_shmctl SYS_shmat SYS_shmdt SYS_socket SYS_socketpair SYS_bind SYS_listen
+	SYS_accept SYS_accept4 SYS_connect SYS_getsockname SYS_getpeername SYS_sendto
+	SYS_recvfrom SYS_setsockopt SYS_getsockopt SYS_shutdown SYS_sendmsg SYS_recvmsg
+	SYS_sendmmsg SYS_recvmmsg SYS_readahead SYS_brk SYS_mmap SYS_munmap SYS_mremap
+	SYS_mprotect SYS_msync SYS_mlock SYS_mlock2 SYS_munlock SYS_mlockall SYS_munlockall
+	SYS_mincore SYS_madvise SYS_remap_file_pages SYS_mbind SYS_get_mempolicy
+	SYS_set_mempolicy SYS_migrate_pages SYS_move_pages SYS_add_key SYS_request_key
+	SYS_keyctl SYS_clone SYS_clone3 SYS_execve SYS_execveat SYS_swapon SYS_swapoff
+	SYS_perf_event_open SYS_fanotify_init SYS_fanotify_mark SYS_name_to_handle_at
+	SYS_open_by_handle_at SYS_process_vm_readv SYS_process_vm_writev SYS_kcmp
+	SYS_seccomp SYS_getrandom SYS_memfd_create SYS_bpf SYS_userfaultfd SYS_membarrier
+	SYS_copy_file_range SYS_pkey_mprotect SYS_pkey_alloc SYS_pkey_free
+	SYS_pidfd_send_signal SYS_pidfd_open SYS_pidfd_getfd SYS_open_tree SYS_move_mount
+	SYS_fsopen SYS_fsconfig SYS_fsmount SYS_fspick SYS_mount_setattr
+	SYS_process_madvise
+};
+
+#[cfg(target_arch = "x86_64")]
+const SYSCALLS_ARCH: &[(&str, libc::c_long)] = syscalls! {
+	SYS_eventfd SYS_epoll_create SYS_epoll_wait SYS_dup2 SYS_inotify_init SYS_mknod
+	SYS_mkdir SYS_rmdir SYS_unlink SYS_symlink SYS_link SYS_rename SYS_renameat
+	SYS_access SYS_chmod SYS_chown SYS_lchown SYS_open SYS_creat SYS_pipe SYS_getdents
+	SYS_sendfile SYS_select SYS_poll SYS_signalfd SYS_readlink SYS_stat SYS_lstat
+	SYS_sync_file_range SYS_utime SYS_utimes SYS_futimesat SYS_alarm SYS_pause
+	SYS_kexec_file_load SYS_getpgrp SYS_getrlimit SYS_setrlimit
+	SYS_arch_prctl SYS_time SYS_fork SYS_vfork SYS_fadvise64 SYS_ioperm SYS_iopl
+	SYS_modify_ldt SYS_uselib SYS_ustat SYS_sysfs SYS__sysctl
+};
+
+#[cfg(not(target_arch = "x86_64"))]
+const SYSCALLS_ARCH: &[(&str, libc::c_long)] = &[];
+
+/// Syscalls that are missing from libc.
+#[cfg(target_arch = "x86_64")]
+const SYSCALLS_MISSING: &[(&str, libc::c_long)] = &[
+	("SYS_create_module", 174),
+	("SYS_io_pgetevents", 333),
+	("SYS_rseq", 334),
+];
+
+#[cfg(target_arch = "aarch64")]
+const SYSCALLS_MISSING: &[(&str, libc::c_long)] = &[
+	("SYS_io_pgetevents", 292),
+	("SYS_rseq", 293),
+];
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const SYSCALLS_MISSING: &[(&str, libc::c_long)] = &[];
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000003e);
+
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc00000b7);
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// Syscall groups, the names that don't exist on this architecture are skipped.
+const GROUPS: &[(&str, &[&str])] = &[
+	("@default", &["arch_prctl", "brk", "clock_getres", "clock_gettime", "clock_nanosleep",
+		"execve", "exit", "exit_group", "futex", "get_robust_list", "getegid", "geteuid",
+		"getgid", "getgroups", "getpgid", "getpgrp", "getpid", "getppid", "getrandom",
+		"getresgid", "getresuid", "getrlimit", "getsid", "gettid", "gettimeofday", "getuid",
+		"membarrier", "mmap", "mprotect", "munmap", "nanosleep", "pause", "prlimit64",
+		"restart_syscall", "rseq", "rt_sigreturn", "sched_getaffinity", "sched_yield",
+		"set_robust_list", "set_tid_address", "time"]),
+	("@aio", &["io_cancel", "io_destroy", "io_getevents", "io_pgetevents", "io_setup",
+		"io_submit", "io_uring_enter", "io_uring_register", "io_uring_setup"]),
+	("@basic-io", &["close", "close_range", "dup", "dup2", "dup3", "lseek", "pread64",
+		"preadv", "preadv2", "pwrite64", "pwritev", "pwritev2", "read", "readv", "write",
+		"writev"]),
+	("@chown", &["chown", "fchown", "fchownat", "lchown"]),
+	("@clock", &["adjtimex", "clock_adjtime", "clock_settime", "settimeofday"]),
+	("@debug", &["lookup_dcookie", "perf_event_open", "pidfd_getfd", "ptrace",
+		"process_vm_readv", "process_vm_writev"]),
+	("@file-system", &["access", "chdir", "chmod", "close", "creat", "faccessat",
+		"faccessat2", "fallocate", "fchdir", "fchmod", "fchmodat", "fcntl", "fgetxattr",
+		"flistxattr", "fremovexattr", "fsetxattr", "fstat", "fstatfs", "ftruncate",
+		"futimesat", "getcwd", "getdents", "getdents64", "getxattr", "inotify_add_watch",
+		"inotify_init", "inotify_init1", "inotify_rm_watch", "lgetxattr", "link", "linkat",
+		"listxattr", "llistxattr", "lremovexattr", "lsetxattr", "lstat", "mkdir", "mkdirat",
+		"mknod", "mknodat", "mmap", "munmap", "newfstatat", "open", "openat", "openat2",
+		"readlink", "readlinkat", "removexattr", "rename", "renameat", "renameat2", "rmdir",
+		"setxattr", "stat", "statfs", "statx", "symlink", "symlinkat", "truncate", "unlink",
+		"unlinkat", "utime", "utimensat", "utimes"]),
+	("@io-event", &["epoll_create", "epoll_create1", "epoll_ctl", "epoll_pwait",
+		"epoll_pwait2", "epoll_wait", "eventfd", "eventfd2", "poll", "ppoll", "pselect6",
+		"select"]),
+	("@ipc", &["memfd_create", "mq_getsetattr", "mq_notify", "mq_open", "mq_timedreceive",
+		"mq_timedsend", "mq_unlink", "msgctl", "msgget", "msgrcv", "msgsnd", "pipe", "pipe2",
+		"process_vm_readv", "process_vm_writev", "semctl", "semget", "semop", "semtimedop",
+		"shmat", "shmctl", "shmdt", "shmget"]),
+	("@keyring", &["add_key", "keyctl", "request_key"]),
+	("@memlock", &["mlock", "mlock2", "mlockall", "munlock", "munlockall"]),
+	("@module", &["create_module", "delete_module", "finit_module", "init_module"]),
+	("@mount", &["chroot", "fsconfig", "fsmount", "fsopen", "fspick", "mount",
+		"mount_setattr", "move_mount", "open_tree", "pivot_root", "umount2"]),
+	("@network-io", &["accept", "accept4", "bind", "connect", "getpeername", "getsockname",
+		"getsockopt", "listen", "recvfrom", "recvmmsg", "recvmsg", "sendmmsg", "sendmsg",
+		"sendto", "setsockopt", "shutdown", "socket", "socketpair"]),
+	("@process", &["capget", "clone", "clone3", "execveat", "fork", "getrusage", "kill",
+		"pidfd_open", "pidfd_send_signal", "prctl", "rt_sigqueueinfo", "rt_tgsigqueueinfo",
+		"setns", "tgkill", "times", "tkill", "unshare", "vfork", "wait4", "waitid"]),
+	("@raw-io", &["ioperm", "iopl"]),
+	("@reboot", &["kexec_file_load", "kexec_load", "reboot"]),
+	("@resources", &["ioprio_set", "mbind", "migrate_pages", "move_pages",
+		"sched_setaffinity", "sched_setattr", "sched_setparam", "sched_setscheduler",
+		"set_mempolicy", "setpriority", "setrlimit"]),
+	("@setuid", &["setfsgid", "setfsuid", "setgid", "setgroups", "setregid", "setresgid",
+		"setresuid", "setreuid", "setuid"]),
+	("@signal", &["rt_sigaction", "rt_sigpending", "rt_sigprocmask", "rt_sigsuspend",
+		"rt_sigtimedwait", "sigaltstack", "signalfd", "signalfd4"]),
+	("@swap", &["swapoff", "swapon"]),
+	("@sync", &["fdatasync", "fsync", "msync", "sync", "sync_file_range", "syncfs"]),
+	("@timer", &["alarm", "getitimer", "setitimer", "timer_create", "timer_delete",
+		"timer_getoverrun", "timer_gettime", "timer_settime", "timerfd_create",
+		"timerfd_gettime", "timerfd_settime", "times"]),
+	("@system-service", &["@aio", "@basic-io", "@chown", "@default", "@file-system",
+		"@io-event", "@ipc", "@keyring", "@memlock", "@network-io", "@process",
+		"@resources", "@setuid", "@signal", "@sync", "@timer", "capset",
+		"copy_file_range", "fadvise64", "flock", "get_mempolicy", "getcpu", "getpriority",
+		"ioctl", "ioprio_get", "kcmp", "madvise", "mincore", "mremap", "name_to_handle_at",
+		"personality", "pkey_alloc", "pkey_free", "pkey_mprotect", "readahead",
+		"remap_file_pages", "sched_get_priority_max", "sched_get_priority_min",
+		"sched_getattr", "sched_getparam", "sched_getscheduler", "sched_rr_get_interval",
+		"sendfile", "setpgid", "setsid", "splice", "sysinfo", "tee", "umask", "uname",
+		"userfaultfd", "vmsplice"]),
+];
+
+const LD_ABS: u16 = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+const JEQ: u16 = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+const JGE: u16 = (libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K) as u16;
+const RET: u16 = (libc::BPF_RET | libc::BPF_K) as u16;
+
+fn stmt(code: u16, k: u32) -> libc::sock_filter {
+	libc::sock_filter { code, jt: 0, jf: 0, k }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+	libc::sock_filter { code, jt, jf, k }
+}
+
+fn syscall(name: &str) -> Option<libc::c_long> {
+	SYSCALLS.iter().chain(SYSCALLS_ARCH).chain(SYSCALLS_MISSING)
+		.find(|(sys, _)| sys.strip_prefix("SYS_") == Some(name))
+		.map(|(_, nr)| *nr)
+}
+
+/// Resolve a syscall or a group name, None if the name is unknown.
+fn resolve(name: &str) -> Option<Vec<libc::c_long>> {
+	if name.starts_with('@') {
+		let (_, names) = GROUPS.iter().find(|(group, _)| *group == name)?;
+		Some(names.iter().flat_map(|name| resolve(name).unwrap_or_default()).collect())
+	} else {
+		syscall(name).map(|nr| vec![nr])
+	}
+}
+
+/// Deserialize syscall-filter, rejecting unknown syscalls and groups.
+pub fn filter<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+	let filter = Vec::<String>::deserialize(deserializer)?;
+
+	for name in &filter {
+		let name = name.strip_prefix('~').unwrap_or(name);
+		if resolve(name).is_none() {
+			return Err(de::Error::custom(format!("unknown syscall or group `{name}`")));
+		}
+	}
+
+	Ok(filter)
+}
+
+/// Compile the filter to BPF.
+///
+/// Names prefixed with "~" are denied. If there are other names,
+/// only these are allowed, otherwise everything that is not denied is allowed.
+pub fn compile(filter: &[String], action: Action) -> io::Result<Vec<libc::sock_filter>> {
+	let arch = AUDIT_ARCH.ok_or(io::Error::new(io::ErrorKind::Unsupported,
+		"syscall filtering is not supported on this architecture"))?;
+
+	let mut allow = vec![];
+	let mut deny = vec![];
+
+	for name in filter {
+		match name.strip_prefix('~') {
+			Some(name) => deny.extend(resolve(name).unwrap_or_default()),
+			None => allow.extend(resolve(name).unwrap_or_default()),
+		}
+	}
+
+	for list in [&mut allow, &mut deny] {
+		list.sort();
+		list.dedup();
+	}
+
+	let action = match action {
+		Action::Eperm => libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+		Action::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+	};
+
+	let mut prog = vec![
+		stmt(LD_ABS, 4),
+		jump(JEQ, arch, 1, 0),
+		stmt(RET, libc::SECCOMP_RET_KILL_PROCESS),
+		stmt(LD_ABS, 0),
+	];
+
+	// Syscalls of the x32 ABI share the architecture with x86_64.
+	if cfg!(target_arch = "x86_64") {
+		prog.push(jump(JGE, 0x40000000, 0, 1));
+		prog.push(stmt(RET, action));
+	}
+
+	for nr in deny {
+		prog.push(jump(JEQ, nr as u32, 0, 1));
+		prog.push(stmt(RET, action));
+	}
+
+	if allow.is_empty() {
+		prog.push(stmt(RET, libc::SECCOMP_RET_ALLOW));
+	} else {
+		for nr in allow {
+			prog.push(jump(JEQ, nr as u32, 0, 1));
+			prog.push(stmt(RET, libc::SECCOMP_RET_ALLOW));
+		}
+		prog.push(stmt(RET, action));
+	}
+
+	Ok(prog)
+}
+
+/// Runs in the child process, it must be the last thing before exec.
+pub unsafe fn install(filter: &[libc::sock_filter]) -> io::Result<()> {
+	let prog = libc::sock_fprog {
+		len: filter.len() as libc::c_ushort,
+		filter: filter.as_ptr() as *mut libc::sock_filter,
+	};
+
+	if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0
+		|| libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &prog) < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	Ok(())
+}
@@ -2,77 +2,667 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use super::{command, socket};
-use std::collections::HashMap;
+use super::{activation, command, condition, graph, health, notify, sandbox, socket, table, timer, watch};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::{CStr, CString};
 use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 use std::os::unix::process::CommandExt;
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::sync::{Arc, Mutex};
-use serde::Deserialize;
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::{de, Deserialize, Deserializer};
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Service {
 	pub control: Control,
 	process: Process,
 	system: Option<System>,
+	sandbox: Option<sandbox::Sandbox>,
+	pub timer: Option<timer::Timer>,
+	pub socket: Option<activation::Socket>,
+	pub path: Option<watch::Watch>,
+	conditions: Option<condition::Conditions>,
+	assertions: Option<condition::Conditions>,
+	pub health: Option<health::Health>,
 	env: Option<HashMap<String, String>>,
+	#[serde(skip)]
+	instance: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Control {
-	#[serde(alias="descr")]
-	_descr: String,
+	descr: String,
 	#[serde(default="xvec")]
-	depends: Vec<String>,
+	pub depends: Vec<String>,
+	#[serde(default="xvec")]
+	pub conflicts: Vec<String>,
 	#[serde(alias="one-time", default="xfalse")]
 	pub one_time: bool,
 	#[serde(default="xfalse")]
 	restart: bool,
 	#[serde(alias="restart-always", default="xfalse")]
 	restart_always: bool,
+	#[serde(alias="restart-limit")]
+	restart_limit: Option<usize>,
+	#[serde(alias="watchdog-sec", default, deserialize_with="timer::seconds")]
+	watchdog_sec: Option<u64>,
+	#[serde(alias="notify-access", default)]
+	notify_access: NotifyAccess,
+	#[serde(alias="notify-ready", default="xfalse")]
+	pub notify_ready: bool,
+	#[serde(alias="on-failure", default="xvec")]
+	pub on_failure: Vec<String>,
+	#[serde(alias="failure-action", default)]
+	failure_action: FailureAction,
+}
+
+/// Processes of the service allowed to send notifications.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all="kebab-case")]
+enum NotifyAccess {
+	#[default]
+	Main,
+	All,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all="kebab-case")]
+enum FailureAction {
+	#[default]
+	None,
+	Reboot,
+	Poweroff,
+	RebootForce,
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 struct Process {
 	#[serde(alias="start-cmd")]
 	start_cmd: Vec<String>,
 	#[serde(alias="stop-cmd")]
 	stop_cmd: Option<Vec<String>>,
 	#[serde(alias="stop-sig", default="sigterm")]
-	stop_sig: i32,
+	stop_sig: Signal,
 	#[serde(alias="restart-cmd")]
 	restart_cmd: Option<Vec<String>>,
 	#[serde(alias="restart-sig")]
-	restart_sig: Option<i32>,
+	restart_sig: Option<Signal>,
 	#[serde(alias="reload-cmd")]
 	reload_cmd: Option<Vec<String>>,
 	#[serde(alias="reload-sig", default="sighup")]
-	reload_sig: i32,
+	reload_sig: Signal,
+	#[serde(alias="env-file", default="xvec")]
+	env_file: Vec<String>,
+	#[serde(alias="clear-env", default="xfalse")]
+	clear_env: bool,
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 struct System {
 	user: Option<String>,
 	group: Option<String>,
 	workdir: Option<String>,
+	#[serde(alias="root-dir")]
+	root_dir: Option<String>,
+	#[serde(alias="root-binds", default="xfalse")]
+	root_binds: bool,
+	#[serde(default, deserialize_with="mode")]
+	umask: Option<libc::mode_t>,
+	#[serde(default, deserialize_with="nice")]
+	nice: Option<i32>,
+	#[serde(alias="io-class")]
+	io_class: Option<sandbox::IoClass>,
+	#[serde(alias="io-priority", default, deserialize_with="io_priority")]
+	io_priority: Option<i32>,
+	#[serde(alias="cpu-affinity", default, deserialize_with="cpu_affinity")]
+	cpu_affinity: Option<Vec<usize>>,
+	#[serde(alias="oom-score-adjust", default, deserialize_with="oom_score_adjust")]
+	oom_score_adjust: Option<i32>,
+	#[serde(alias="scheduling-policy")]
+	scheduling_policy: Option<sandbox::SchedulingPolicy>,
+	#[serde(alias="scheduling-priority", default, deserialize_with="scheduling_priority")]
+	scheduling_priority: Option<i32>,
 }
 
-fn xvec() -> Vec<String> {
+pub fn xvec() -> Vec<String> {
 	vec![]
 }
 
-fn xfalse() -> bool {
+pub fn xfalse() -> bool {
 	false
 }
 
-fn sighup() -> i32 {
-	libc::SIGHUP
+fn sighup() -> Signal {
+	Signal(libc::SIGHUP)
+}
+
+fn sigterm() -> Signal {
+	Signal(libc::SIGTERM)
+}
+
+fn bounded<'de, D: Deserializer<'de>>(deserializer: D, min: i32, max: i32)
+	-> Result<Option<i32>, D::Error> {
+	let value = i32::deserialize(deserializer)?;
+	if value < min || value > max {
+		return Err(de::Error::custom(format!("expected a value between {min} and {max}")));
+	}
+	Ok(Some(value))
+}
+
+/// A signal, written as a number or a name like "SIGTERM", "TERM" or "SIGRTMIN+3".
+#[derive(Clone, Copy)]
+pub struct Signal(pub i32);
+
+const SIGNALS: &[(&str, i32)] = &[
+	("HUP", libc::SIGHUP), ("INT", libc::SIGINT), ("QUIT", libc::SIGQUIT),
+	("ILL", libc::SIGILL), ("TRAP", libc::SIGTRAP), ("ABRT", libc::SIGABRT),
+	("BUS", libc::SIGBUS), ("FPE", libc::SIGFPE), ("KILL", libc::SIGKILL),
+	("USR1", libc::SIGUSR1), ("SEGV", libc::SIGSEGV), ("USR2", libc::SIGUSR2),
+	("PIPE", libc::SIGPIPE), ("ALRM", libc::SIGALRM), ("TERM", libc::SIGTERM),
+	("STKFLT", libc::SIGSTKFLT), ("CHLD", libc::SIGCHLD), ("CONT", libc::SIGCONT),
+	("STOP", libc::SIGSTOP), ("TSTP", libc::SIGTSTP), ("TTIN", libc::SIGTTIN),
+	("TTOU", libc::SIGTTOU), ("URG", libc::SIGURG), ("XCPU", libc::SIGXCPU),
+	("XFSZ", libc::SIGXFSZ), ("VTALRM", libc::SIGVTALRM), ("PROF", libc::SIGPROF),
+	("WINCH", libc::SIGWINCH), ("IO", libc::SIGIO), ("PWR", libc::SIGPWR),
+	("SYS", libc::SIGSYS), ("IOT", libc::SIGIOT), ("POLL", libc::SIGPOLL),
+];
+
+impl Signal {
+	fn parse(name: &str) -> Option<Signal> {
+		let name = name.to_ascii_uppercase();
+		let name = name.strip_prefix("SIG").unwrap_or(&name);
+		let (rtmin, rtmax) = (libc::SIGRTMIN(), libc::SIGRTMAX());
+
+		let signal = match name {
+			"RTMIN" => rtmin,
+			"RTMAX" => rtmax,
+			_ => if let Some(offset) = name.strip_prefix("RTMIN+") {
+				rtmin + offset.parse::<u8>().ok()? as i32
+			} else if let Some(offset) = name.strip_prefix("RTMAX-") {
+				rtmax - offset.parse::<u8>().ok()? as i32
+			} else {
+				let (_, signal) = SIGNALS.iter().find(|(signal, _)| *signal == name)?;
+				return Some(Signal(*signal));
+			},
+		};
+
+		(rtmin ..= rtmax).contains(&signal).then_some(Signal(signal))
+	}
+
+	pub fn name(&self) -> String {
+		let (rtmin, rtmax) = (libc::SIGRTMIN(), libc::SIGRTMAX());
+
+		match self.0 {
+			signal if signal == rtmin => "SIGRTMIN".into(),
+			signal if signal == rtmax => "SIGRTMAX".into(),
+			signal if signal > rtmin && signal < rtmax => format!("SIGRTMIN+{}", signal - rtmin),
+			signal => match SIGNALS.iter().find(|(_, number)| *number == signal) {
+				Some((name, _)) => format!("SIG{name}"),
+				None => signal.to_string(),
+			},
+		}
+	}
+}
+
+impl std::fmt::Debug for Signal {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "{}", self.name())
+	}
+}
+
+struct SignalVisitor;
+
+impl<'de> de::Visitor<'de> for SignalVisitor {
+	type Value = Signal;
+
+	fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "a signal number or name")
+	}
+
+	fn visit_i64<E: de::Error>(self, number: i64) -> Result<Signal, E> {
+		let max = libc::SIGRTMAX();
+		match number {
+			number if (1 ..= max as i64).contains(&number) => Ok(Signal(number as i32)),
+			_ => Err(E::custom(format!("expected a signal number between 1 and {max}"))),
+		}
+	}
+
+	fn visit_str<E: de::Error>(self, name: &str) -> Result<Signal, E> {
+		Signal::parse(name).ok_or_else(|| E::custom(format!("unknown signal `{name}`")))
+	}
+}
+
+impl<'de> Deserialize<'de> for Signal {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Signal, D::Error> {
+		deserializer.deserialize_any(SignalVisitor)
+	}
+}
+
+fn nice<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i32>, D::Error> {
+	bounded(deserializer, -20, 19)
+}
+
+fn io_priority<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i32>, D::Error> {
+	bounded(deserializer, 0, 7)
+}
+
+fn oom_score_adjust<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i32>, D::Error> {
+	bounded(deserializer, -1000, 1000)
+}
+
+fn scheduling_priority<'de, D: Deserializer<'de>>(deserializer: D)
+	-> Result<Option<i32>, D::Error> {
+	bounded(deserializer, 0, 99)
+}
+
+pub fn mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<libc::mode_t>, D::Error> {
+	let mode = String::deserialize(deserializer)?;
+	match libc::mode_t::from_str_radix(&mode, 8) {
+		Ok(mode) if mode <= 0o777 => Ok(Some(mode)),
+		_ => Err(de::Error::custom("expected an octal mode, like \"0022\"")),
+	}
+}
+
+/// Parse a list of CPUs, like "0-3,6".
+fn cpu_affinity<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<usize>>, D::Error> {
+	let affinity = String::deserialize(deserializer)?;
+	let mut cpus = vec![];
+
+	for range in affinity.split([',', ' ']).filter(|range| ! range.is_empty()) {
+		let (first, last) = range.split_once('-').unwrap_or((range, range));
+		match (first.parse::<usize>(), last.parse::<usize>()) {
+			(Ok(first), Ok(last)) if first <= last && last < libc::CPU_SETSIZE as usize => {
+				cpus.extend(first ..= last);
+			},
+			_ => return Err(de::Error::custom(format!("invalid CPU range `{range}`"))),
+		}
+	}
+
+	Ok(Some(cpus))
+}
+
+impl Service {
+	/// Details shown by `control status --verbose`.
+	pub fn properties(&self) -> Vec<(&'static str, String)> {
+		let mut properties = vec![
+			("Description", self.control.descr.clone()),
+			("Start command", self.process.start_cmd.join(" ")),
+		];
+
+		let process = &self.process;
+		for (key, command, signal) in [
+			("Stop", &process.stop_cmd, Some(process.stop_sig)),
+			("Restart", &process.restart_cmd, process.restart_sig),
+			("Reload", &process.reload_cmd, Some(process.reload_sig)),
+		] {
+			match (command, signal) {
+				(Some(command), _) => properties.push((key, command.join(" "))),
+				(None, Some(signal)) => properties.push((key, signal.name())),
+				(None, None) => {},
+			}
+		}
+
+		if ! self.control.depends.is_empty() {
+			properties.push(("Depends", self.control.depends.join(", ")));
+		}
+
+		if ! self.control.conflicts.is_empty() {
+			properties.push(("Conflicts", self.control.conflicts.join(", ")));
+		}
+
+		if let Some(restart_limit) = self.control.restart_limit {
+			properties.push(("Restart limit", format!("{restart_limit} per minute")));
+		}
+
+		if let Some(watchdog_sec) = self.control.watchdog_sec {
+			properties.push(("Watchdog", timer::format_span(watchdog_sec)));
+		}
+
+		if self.control.notify_ready {
+			properties.push(("Ready", "on READY=1".into()));
+		}
+
+		if self.control.notify_access == NotifyAccess::All {
+			properties.push(("Notify access", "all".into()));
+		}
+
+		if ! self.control.on_failure.is_empty() {
+			properties.push(("On failure", self.control.on_failure.join(", ")));
+		}
+
+		match self.control.failure_action {
+			FailureAction::None => {},
+			FailureAction::Reboot => properties.push(("Failure action", "reboot".into())),
+			FailureAction::Poweroff => properties.push(("Failure action", "poweroff".into())),
+			FailureAction::RebootForce => properties.push(("Failure action", "reboot-force".into())),
+		}
+
+		if let Some(timer) = &self.timer {
+			properties.extend(timer.properties());
+		}
+
+		if let Some(socket) = &self.socket {
+			properties.extend(socket.properties());
+		}
+
+		if let Some(path) = &self.path {
+			properties.extend(path.properties());
+		}
+
+		if let Some(conditions) = &self.conditions {
+			properties.push(("Conditions", conditions.describe().join(", ")));
+		}
+
+		if let Some(assertions) = &self.assertions {
+			properties.push(("Assertions", assertions.describe().join(", ")));
+		}
+
+		if let Some(health) = &self.health {
+			properties.extend(health.properties());
+		}
+
+		if let Some(system) = &self.system {
+			let io_priority = match (system.io_class, system.io_priority) {
+				(None, None) => None,
+				(class, priority) => {
+					let class = class.unwrap_or(sandbox::IoClass::BestEffort).name();
+					let priority = priority.map(|p| format!(" {p}")).unwrap_or_default();
+					Some(format!("{class}{priority}"))
+				},
+			};
+
+			let scheduling = system.scheduling_policy.map(|policy| {
+				let priority = system.scheduling_priority;
+				let priority = priority.map(|p| format!(" {p}")).unwrap_or_default();
+				format!("{}{priority}", policy.name())
+			});
+
+			let cpu_affinity = system.cpu_affinity.as_ref().map(|cpus| {
+				cpus.iter().map(|cpu| cpu.to_string()).collect::<Vec<_>>().join(",")
+			});
+
+			for (key, value) in [
+				("User", system.user.clone()),
+				("Group", system.group.clone()),
+				("Workdir", system.workdir.clone()),
+				("Root dir", system.root_dir.clone()),
+				("Umask", system.umask.map(|umask| format!("{umask:04o}"))),
+				("Nice", system.nice.map(|nice| nice.to_string())),
+				("IO priority", io_priority),
+				("CPU affinity", cpu_affinity),
+				("OOM score adjust", system.oom_score_adjust.map(|oom| oom.to_string())),
+				("Scheduling", scheduling),
+			] {
+				if let Some(value) = value {
+					properties.push((key, value));
+				}
+			}
+		}
+
+		properties
+	}
+}
+
+/// Find an executable like execvp does, relative to the root directory.
+fn executable(program: &str, path: &str, root: &str) -> bool {
+	let is_executable = |file: String| {
+		fs::metadata(format!("{root}{file}"))
+			.is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+	};
+
+	if program.contains('/') {
+		is_executable(program.into())
+	} else {
+		path.split(':').any(|dir| is_executable(format!("{dir}/{program}")))
+	}
+}
+
+impl Service {
+	/// Problems that would prevent the service from running, with the key they come from.
+	///
+	/// Values that use ${VAR} expansion are only known at start, they are not checked.
+	pub fn problems(&self) -> Vec<(&'static str, String)> {
+		let mut problems = vec![];
+
+		let system = self.system.as_ref();
+		let root_dir = system.and_then(|system| system.root_dir.as_deref());
+		let root = root_dir.unwrap_or("").trim_end_matches('/');
+
+		if let Some(root_dir) = root_dir {
+			if ! Path::new(root_dir).is_dir() {
+				problems.push(("system.root-dir", format!("directory `{root_dir}` does not exist")));
+			} else if system.is_some_and(|system| system.root_binds) {
+				for file in sandbox::ROOT_BINDS {
+					if Path::new(file).exists() && ! Path::new(&format!("{root}{file}")).exists() {
+						problems.push(("system.root-binds", format!("`{file}` does not exist in `{root_dir}`")));
+					}
+				}
+			}
+		}
+
+		let path = match self.env.as_ref().and_then(|env| env.get("PATH")) {
+			Some(path) => path.clone(),
+			None if self.process.clear_env => DEFAULT_PATH.into(),
+			None => std::env::var("PATH").unwrap_or(DEFAULT_PATH.into()),
+		};
+
+		for (key, command) in [
+			("process.start-cmd", Some(&self.process.start_cmd)),
+			("process.stop-cmd", self.process.stop_cmd.as_ref()),
+			("process.restart-cmd", self.process.restart_cmd.as_ref()),
+			("process.reload-cmd", self.process.reload_cmd.as_ref()),
+		] {
+			match command.map(|command| command.first()) {
+				None => {},
+				Some(None) => problems.push((key, "empty command".into())),
+				Some(Some(program)) if ! program.contains("${") && ! executable(program, &path, root) => {
+					problems.push((key, format!("`{program}` is not an executable")));
+				},
+				Some(Some(_)) => {},
+			}
+		}
+
+		for env_file in &self.process.env_file {
+			if ! env_file.starts_with('-') && ! Path::new(env_file).is_file() {
+				problems.push(("process.env-file", format!("file `{env_file}` does not exist")));
+			}
+		}
+
+		if let Some(workdir) = system.and_then(|system| system.workdir.as_ref()) {
+			if ! workdir.contains("${") && ! Path::new(&format!("{root}{workdir}")).is_dir() {
+				problems.push(("system.workdir", format!("directory `{workdir}` does not exist")));
+			}
+		}
+
+		if let Some(priority) = system.and_then(|system| system.scheduling_priority) {
+			let policy = system.and_then(|system| system.scheduling_policy);
+			match policy {
+				Some(sandbox::SchedulingPolicy::Fifo | sandbox::SchedulingPolicy::Rr) if priority == 0 => {
+					problems.push(("system.scheduling-priority", "priority 0 is only for the other, batch and idle policies".into()));
+				},
+				Some(sandbox::SchedulingPolicy::Fifo | sandbox::SchedulingPolicy::Rr) => {},
+				Some(_) if priority != 0 => {
+					problems.push(("system.scheduling-priority", format!("priority {priority} is only for the fifo and rr policies")));
+				},
+				Some(_) => {},
+				None => problems.push(("system.scheduling-priority", "priority without scheduling-policy".into())),
+			}
+		}
+
+		if let Some(socket) = &self.socket {
+			problems.extend(socket.problems());
+		}
+
+		if self.control.watchdog_sec.is_some() && root_dir.is_some() {
+			problems.push(("control.watchdog-sec", "the notify socket is not reachable from `root-dir`".into()));
+		}
+
+		if self.control.notify_ready && root_dir.is_some() {
+			problems.push(("control.notify-ready", "the notify socket is not reachable from `root-dir`".into()));
+		}
+
+		if let Some(path) = &self.path {
+			problems.extend(path.problems());
+		}
+
+		if let Some(conditions) = &self.conditions {
+			problems.extend(conditions.problems("conditions"));
+		}
+
+		if let Some(assertions) = &self.assertions {
+			problems.extend(assertions.problems("assertions"));
+		}
+
+		// Health checks run outside of the root directory.
+		match self.health.as_ref().map(|health| health.cmd.first()) {
+			None => {},
+			Some(None) => problems.push(("health.cmd", "empty command".into())),
+			Some(Some(program)) if ! program.contains("${") && ! executable(program, &path, "") => {
+				problems.push(("health.cmd", format!("`{program}` is not an executable")));
+			},
+			Some(Some(_)) => {},
+		}
+
+		if self.timer.as_ref().is_some_and(|timer| ! timer.elapses()) {
+			problems.push(("timer", "timer without on-calendar, on-boot-sec or on-unit-active-sec".into()));
+		}
+
+		for (key, names) in [
+			("control.depends", &self.control.depends),
+			("control.conflicts", &self.control.conflicts),
+			("control.on-failure", &self.control.on_failure),
+		] {
+			for name in names {
+				if instance(name).is_some_and(|(_, instance)| instance.is_empty()) {
+					problems.push((key, format!("`{name}` is a template")));
+				} else if ! Path::new(&service_file(name)).exists() {
+					problems.push((key, format!("service `{name}` does not exist")));
+				}
+			}
+		}
+
+		problems
+	}
 }
 
-fn sigterm() -> i32 {
-	libc::SIGTERM
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Read KEY=VALUE lines, the "-" prefix means that a missing file is not an error.
+fn env_file(path: &str, env: &mut HashMap<String, String>) -> Result<(), Error> {
+	let (path, optional) = match path.strip_prefix('-') {
+		Some(path) => (path, true),
+		None => (path, false),
+	};
+
+	let text = match fs::read_to_string(path) {
+		Ok(text) => text,
+		Err(_) if optional => return Ok(()),
+		Err(_) => return Err(Error::CannotSpawn),
+	};
+
+	for line in text.lines().map(str::trim) {
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+
+		let line = line.strip_prefix("export ").unwrap_or(line);
+		if let Some((key, value)) = line.split_once('=') {
+			let value = value.trim();
+			let value = match value.as_bytes() {
+				[b'"', .., b'"'] | [b'\'', .., b'\''] => &value[1 .. value.len() - 1],
+				_ => value,
+			};
+			env.insert(key.trim().into(), value.into());
+		}
+	}
+
+	Ok(())
+}
+
+/// Expand ${VAR} references, unknown variables expand to an empty string.
+fn expand(arg: &str, lookup: &dyn Fn(&str) -> Option<String>) -> String {
+	let mut expanded = String::with_capacity(arg.len());
+	let mut rest = arg;
+
+	while let Some(start) = rest.find("${") {
+		let name_len = rest[start + 2 ..].find('}');
+		let name = name_len.map(|len| &rest[start + 2 .. start + 2 + len]);
+
+		match name {
+			Some(name) if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => {
+				expanded.push_str(&rest[.. start]);
+				expanded.push_str(&lookup(name).unwrap_or_default());
+				rest = &rest[start + 3 + name.len() ..];
+			},
+			_ => {
+				expanded.push_str(&rest[.. start + 2]);
+				rest = &rest[start + 2 ..];
+			},
+		}
+	}
+
+	expanded.push_str(rest);
+	expanded
+}
+
+impl Service {
+	/// Environment of the service processes, on top of the inherited one
+	/// unless clear-env is set.
+	fn environment(&self, pid: Option<i32>) -> Result<HashMap<String, String>, Error> {
+		let mut env = HashMap::new();
+
+		env_file(&format!("-{}", envfile()), &mut env)?;
+		for path in &self.process.env_file {
+			env_file(path, &mut env)?;
+		}
+
+		if let Some(vars) = &self.env {
+			env.extend(vars.clone());
+		}
+
+		if let Some(instance) = &self.instance {
+			env.insert("INSTANCE".into(), instance.clone());
+		}
+
+		if let Some(pid) = pid {
+			env.insert("MAINPID".into(), pid.to_string());
+		}
+
+		Ok(env)
+	}
+
+	fn command(&self, command: &[String], pid: Option<i32>) -> Result<Command, Error> {
+		let env = self.environment(pid)?;
+		let clear_env = self.process.clear_env;
+
+		let lookup = |name: &str| match env.get(name) {
+			Some(value) => Some(value.clone()),
+			None if clear_env && name == "PATH" => Some(DEFAULT_PATH.into()),
+			None if clear_env => None,
+			None => std::env::var(name).ok(),
+		};
+
+		let command: Vec<_> = command.iter().map(|arg| expand(arg, &lookup)).collect();
+		let mut process = Command::new(&command[0]);
+		process.args(&command[1..]);
+
+		if clear_env {
+			process.env_clear();
+			process.env("PATH", DEFAULT_PATH);
+		}
+		process.envs(env);
+
+		Ok(process)
+	}
 }
 
 type ResultService = Result<Service, toml::de::Error>;
@@ -80,6 +670,8 @@ type ResultService = Result<Service, toml::de::Error>;
 #[derive(Debug)]
 pub struct Meta {
 	pub exists: bool,
+	pub masked: bool,
+	pub template: bool,
 	pub valid: bool,
 	pub enabled: bool,
 	pub running: bool,
@@ -104,6 +696,10 @@ pub fn confdir_enabled() -> String {
 	format!("{}/enabled", confdir())
 }
 
+pub fn confdir_masked() -> String {
+	format!("{}/masked", confdir())
+}
+
 pub fn rundir() -> String {
 	let euid = unsafe { libc::geteuid() };
 	if euid == 0 {
@@ -117,6 +713,24 @@ pub fn rundir() -> String {
 	}
 }
 
+/// Directory of the state kept across reboots.
+pub fn statedir() -> String {
+	let euid = unsafe { libc::geteuid() };
+	if euid == 0 {
+		String::from("/var/lib/control")
+	} else {
+		let home = unsafe {
+			let pw = libc::getpwuid(euid);
+			CStr::from_ptr((*pw).pw_dir).to_string_lossy()
+		};
+		format!("{home}/.control/state")
+	}
+}
+
+pub fn envfile() -> String {
+	format!("{}/environment", confdir())
+}
+
 pub fn control_lock() -> String {
 	format!("{}/control.lock", rundir())
 }
@@ -125,6 +739,37 @@ pub fn control_sock() -> String {
 	format!("{}/control.sock", rundir())
 }
 
+pub fn notify_sock() -> String {
+	format!("{}/notify", rundir())
+}
+
+pub fn timingfile() -> String {
+	format!("{}/timing", rundir())
+}
+
+pub fn timersfile() -> String {
+	format!("{}/timers", rundir())
+}
+
+pub fn timer_stamp(service_name: &str) -> String {
+	format!("{}/timers/{service_name}", statedir())
+}
+
+/// Why the last start of the service was skipped or failed, shown by `control status`.
+pub fn conditionfile(service_name: &str) -> String {
+	format!("{}/{service_name}.condition", rundir())
+}
+
+pub fn condition(service_name: &str) -> Option<String> {
+	let condition = fs::read_to_string(conditionfile(service_name)).ok()?;
+	Some(condition.trim_end().into())
+}
+
+/// State of the health checks of the running service.
+pub fn healthfile(service_name: &str) -> String {
+	format!("{}/{service_name}.health", rundir())
+}
+
 pub fn pidfile(service_name: &str) -> String {
 	format!("{}/{service_name}.pid", rundir())
 }
@@ -146,68 +791,223 @@ pub fn pidfile_del(service_name: &str) {
 	fs::remove_file(pidfile).unwrap();
 }
 
-pub fn load(service_name: &str) -> ResultService {
-	let service_file = format!("{}/{service_name}.toml", confdir());
-	let service_file = fs::read_to_string(service_file).unwrap_or(String::new());
-	toml::from_str(&service_file)
+/// Split "getty@tty1" into the template "getty@" and the instance "tty1".
+pub fn instance(service_name: &str) -> Option<(&str, &str)> {
+	let at = service_name.find('@')?;
+	let (template, instance) = (&service_name[..= at], &service_name[at + 1 ..]);
+	let valid = |c: char| c.is_ascii_alphanumeric() || "_-.:".contains(c);
+
+	if instance.chars().all(valid) {
+		Some((template, instance))
+	} else {
+		None
+	}
 }
 
-pub fn meta(service_name: &str) -> Meta {
-	let service_file = format!("{}/{service_name}.toml", confdir());
-	let exists = PathBuf::from(service_file).exists();
-	let service = load(service_name);
-	let valid = service.is_ok();
+/// Directories searched for service files, from the highest priority:
+/// admin, runtime (generated at runtime) and vendor (installed by packages).
+pub fn confdirs() -> Vec<String> {
+	let euid = unsafe { libc::geteuid() };
+	let runtime = format!("{}/services", rundir());
 
-	let enabled = if exists && valid {
-		let service_file = format!("{}/{service_name}.toml", confdir_enabled());
-		PathBuf::from(service_file).exists()
+	if euid == 0 {
+		vec![confdir(), runtime, String::from("/usr/lib/control")]
 	} else {
-		false
-	};
+		vec![confdir(), runtime]
+	}
+}
 
-	let pid = if exists && valid {
-		pidfile_get(service_name)
-	} else {
-		None
-	};
+/// Service names and the files they come from, a file hides the lower priority ones.
+pub fn list() -> BTreeMap<String, String> {
+	let mut services = BTreeMap::new();
 
-	let running = pid.is_some();
+	for confdir in confdirs() {
+		let Ok(entries) = fs::read_dir(&confdir) else {
+			continue;
+		};
 
-	Meta { exists, valid, enabled, running, service, pid }
+		for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+			if path.extension().is_some_and(|ext| ext == "toml") {
+				let service_name = path.file_stem().unwrap().to_string_lossy().to_string();
+				let service_file = path.to_string_lossy().to_string();
+				services.entry(service_name).or_insert(service_file);
+			}
+		}
+	}
+
+	services
 }
 
-pub fn order(service_names: Vec<String>) -> Vec<String> {
-	let mut services: HashMap<String, Vec<String>>
-		= HashMap::with_capacity(service_names.len());
+/// Find the service file in the config directories.
+///
+/// Instances use the template file, unless they have their own.
+/// A mask hides every service file, missing services resolve to the admin directory.
+pub fn service_file(service_name: &str) -> String {
+	let mut names = vec![service_name];
 
-	for service_name in service_names {
-		let service = load(&service_name);
-		if let Ok(service) = service {
-			let depends = service.control.depends;
-			services.insert(service_name.to_string(), depends);
+	if let Some((template, instance)) = instance(service_name) {
+		if ! instance.is_empty() {
+			names.push(template);
 		}
 	}
 
-	let mut order: Vec<String> = Vec::with_capacity(services.len());
+	for name in names {
+		for confdir in [confdir_masked()].into_iter().chain(confdirs()) {
+			let service_file = format!("{confdir}/{name}.toml");
+			if fs::symlink_metadata(&service_file).is_ok() {
+				return service_file;
+			}
+		}
+	}
 
-	while order.len() < order.capacity() {
-		let len = order.len();
+	format!("{}/{service_name}.toml", confdir())
+}
 
-		for service_name in services.keys() {
-			let depends = services.get(service_name).unwrap();
-			let depends: Vec<_> = depends.iter().cloned()
-				.filter(|e| services.contains_key(e)).collect();
-			if depends.iter().all(|e| order.contains(e)) {
-				order.push(service_name.to_string());
+/// Services are masked by symlinking their file to /dev/null.
+pub fn masked(service_name: &str) -> bool {
+	let service_file = service_file(service_name);
+	fs::canonicalize(service_file).is_ok_and(|path| path == Path::new("/dev/null"))
+}
+
+/// The file masking the service, it takes priority over the config directories.
+pub fn maskfile(service_name: &str) -> String {
+	format!("{}/{service_name}.toml", confdir_masked())
+}
+
+/// Drop-ins of the file named service_file, a fragment hides the lower priority
+/// fragments of the same name.
+fn dropins(service_file: &str) -> Vec<String> {
+	let mut dropins = BTreeMap::new();
+
+	for confdir in confdirs().iter().rev() {
+		let Ok(entries) = fs::read_dir(format!("{confdir}/{service_file}.d")) else {
+			continue;
+		};
+
+		for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+			if path.extension().is_some_and(|ext| ext == "toml") {
+				let name = path.file_name().unwrap().to_string_lossy().to_string();
+				dropins.insert(name, path.to_string_lossy().to_string());
 			}
 		}
+	}
+
+	dropins.into_values().collect()
+}
+
+/// The service file followed by its drop-in fragments, in the order they are merged.
+///
+/// Instances use the drop-ins of the template first, then their own.
+pub fn service_files(service_name: &str) -> Vec<String> {
+	let service_file = service_file(service_name);
+	let file_name = Path::new(&service_file).file_name().unwrap().to_string_lossy().to_string();
+	let own_name = format!("{service_name}.toml");
 
-		for service_name in &order[len..] {
-			services.remove(service_name);
+	let mut service_files = vec![service_file];
+	service_files.extend(dropins(&file_name));
+	if own_name != file_name {
+		service_files.extend(dropins(&own_name));
+	}
+
+	service_files
+}
+
+pub fn read(service_name: &str, service_file: &str) -> String {
+	let text = fs::read_to_string(service_file).unwrap_or_default();
+
+	match instance(service_name) {
+		Some((_, instance)) => text.replace("%i", instance).replace("${INSTANCE}", instance),
+		None => text,
+	}
+}
+
+/// Deep-merge the fragment over the table, tables are merged and other values replaced.
+fn merge(table: &mut toml::Table, fragment: toml::Table, source: &str, path: &str,
+	sources: &mut Vec<(String, String)>) {
+	for (key, value) in fragment {
+		let path = if path.is_empty() { key.clone() } else { format!("{path}.{key}") };
+
+		match (table.get_mut(&key), value) {
+			(Some(toml::Value::Table(table)), toml::Value::Table(fragment)) => {
+				merge(table, fragment, source, &path, sources);
+			},
+			(_, value) => {
+				sources.retain(|(key, _)| key != &path && ! key.starts_with(&format!("{path}.")));
+				if let toml::Value::Table(fragment) = value {
+					let mut empty = toml::Table::new();
+					merge(&mut empty, fragment, source, &path, sources);
+					table.insert(key, toml::Value::Table(empty));
+				} else {
+					sources.push((path, source.into()));
+					table.insert(key, value);
+				}
+			},
 		}
 	}
+}
+
+/// The service file merged with its drop-ins, and the file each key comes from.
+pub fn merged(service_name: &str) -> Result<(toml::Table, Vec<(String, String)>), toml::de::Error> {
+	let mut table = toml::Table::new();
+	let mut sources = vec![];
+
+	for service_file in service_files(service_name) {
+		let fragment: toml::Table = toml::from_str(&read(service_name, &service_file))?;
+		merge(&mut table, fragment, &service_file, "", &mut sources);
+	}
+
+	Ok((table, sources))
+}
+
+pub fn load(service_name: &str) -> ResultService {
+	let service_files = service_files(service_name);
+
+	let mut service: Service = if service_files.len() == 1 {
+		toml::from_str(&read(service_name, &service_files[0]))?
+	} else {
+		let (table, _) = merged(service_name)?;
+		toml::Value::Table(table).try_into()?
+	};
+
+	service.instance = instance(service_name).map(|(_, instance)| instance.into());
+	Ok(service)
+}
+
+pub fn enabled(service_name: &str) -> bool {
+	let service_file = format!("{}/{service_name}.toml", confdir_enabled());
+	PathBuf::from(service_file).exists()
+}
+
+/// Whether the services can't run together, either of them listing the other.
+pub fn conflicts(service_name: &str, service: &Service, other_name: &str) -> bool {
+	service.control.conflicts.iter().any(|name| name == other_name)
+		|| load(other_name).is_ok_and(|other| other.control.conflicts.iter().any(|name| name == service_name))
+}
 
-	order
+pub fn meta(service_name: &str) -> Meta {
+	let exists = PathBuf::from(service_file(service_name)).exists();
+	let masked = masked(service_name);
+	let template = service_name.ends_with('@');
+	let service = load(service_name);
+	let valid = service.is_ok();
+
+	let enabled = exists && valid && enabled(service_name);
+
+	let pid = if exists && valid {
+		pidfile_get(service_name)
+	} else {
+		None
+	};
+
+	let running = pid.is_some();
+
+	Meta { exists, masked, template, valid, enabled, running, service, pid }
+}
+
+/// Start order of the services, services stuck in a dependency cycle come last.
+pub fn order(service_names: Vec<String>) -> Vec<String> {
+	let (levels, cyclic) = graph::Graph::new(&service_names).levels(&service_names);
+	levels.into_iter().flatten().chain(cyclic).collect()
 }
 
 #[derive(PartialEq)]
@@ -216,11 +1016,53 @@ pub enum Error {
 	CannotKill,
 	NotFound,
 	NoDaemon,
+	Masked,
+	Condition,
+	Assertion,
+	/// A one-time service exited with a failure, with its exit status.
+	Failed(String),
 }
 
-fn spawn_start(service: &Service) -> Result<i32, Error> {
-	let mut process = Command::new(&service.process.start_cmd[0]);
-	process.args(&service.process.start_cmd[1..]);
+fn spawn_start(service_name: &str, service: &Service, connection: Option<activation::Connection>)
+	-> Result<i32, Error> {
+	let mut process = service.command(&service.process.start_cmd, None)?;
+	let mut listen = None;
+
+	// Watchdog pings and readiness are sent like systemd's sd_notify() does. They are
+	// set before the command is rebuilt for its sockets, which can't take more variables.
+	if service.control.watchdog_sec.is_some() || service.control.notify_ready {
+		process.env("NOTIFY_SOCKET", notify_sock());
+	}
+	if let Some(watchdog_sec) = service.control.watchdog_sec {
+		process.env("WATCHDOG_USEC", (watchdog_sec * 1_000_000).to_string());
+	}
+	let connected = connection.is_some();
+
+	if let Some(connection) = connection {
+		let Ok(fd) = connection.fd.try_clone() else {
+			return Err(Error::CannotSpawn);
+		};
+		process.stdin(fd).stdout(connection.fd);
+		if let Some(remote) = connection.remote {
+			process.env("REMOTE_ADDR", remote.ip().to_string());
+			process.env("REMOTE_PORT", remote.port().to_string());
+		}
+	} else if let Some(socket) = &service.socket {
+		// Instances are only started for the connections they handle.
+		if socket.accept {
+			return Err(Error::NotFound);
+		}
+
+		let Ok(fds) = activation::sockets(service_name, socket) else {
+			return Err(Error::CannotSpawn);
+		};
+		let clear_env = service.process.clear_env;
+		let (command, sockets) = activation::command(&process, clear_env, service_name, fds);
+		process = command;
+		listen = Some(sockets);
+	}
+
+	let mut jail = sandbox::Jail::new();
 
 	if let Some(system) = &service.system {
 		let euid = unsafe { libc::geteuid() };
@@ -234,7 +1076,7 @@ fn spawn_start(service: &Service) -> Result<i32, Error> {
 				unsafe {
 					let user = libc::getpwnam(str_into_raw(user));
 					if ! user.is_null() {
-						process.uid((*user).pw_uid);
+						jail.uid((*user).pw_uid);
 					}
 				};
 			}
@@ -243,27 +1085,81 @@ fn spawn_start(service: &Service) -> Result<i32, Error> {
 				unsafe {
 					let group = libc::getgrnam(str_into_raw(group));
 					if ! group.is_null() {
-						process.gid((*group).gr_gid);
+						jail.gid((*group).gr_gid);
 					}
 				};
 			}
 		}
 
+		if let Some(root_dir) = &system.root_dir {
+			if jail.root_dir(root_dir, system.root_binds).is_err() {
+				return Err(Error::CannotSpawn);
+			}
+		}
+
 		if let Some(workdir) = &system.workdir {
-			process.current_dir(workdir);
+			jail.workdir(workdir);
+		}
+
+		if let Some(umask) = system.umask {
+			jail.umask(umask);
+		}
+
+		if let Some(nice) = system.nice {
+			jail.nice(nice);
+		}
+
+		if system.io_class.is_some() || system.io_priority.is_some() {
+			let io_class = system.io_class.unwrap_or(sandbox::IoClass::BestEffort);
+			jail.io_priority(io_class, system.io_priority);
+		}
+
+		if let Some(cpus) = &system.cpu_affinity {
+			jail.cpu_affinity(cpus);
+		}
+
+		if let Some(oom_score_adjust) = system.oom_score_adjust {
+			jail.oom_score_adjust(oom_score_adjust);
+		}
+
+		if let Some(policy) = system.scheduling_policy {
+			jail.scheduling(policy, system.scheduling_priority);
 		}
 	}
 
-	if let Some(env) = &service.env {
-		process.envs(env);
-	};
+	if let Some(sandbox) = &service.sandbox {
+		if jail.sandbox(sandbox).is_err() {
+			return Err(Error::CannotSpawn);
+		}
+	}
+
+	let reserved = listen.as_ref().map(activation::Listen::reserve);
+
+	unsafe {
+		process.pre_exec(move || {
+			if let Some(listen) = &mut listen {
+				listen.fds()?;
+			}
+			jail.enter()?;
+			if let Some(listen) = &mut listen {
+				listen.environ();
+			}
+			Ok(())
+		});
+	}
 
 	let child = process.spawn();
+	drop(reserved);
 
 	if let Ok(mut child) = child {
-		if service.control.one_time {
-			child.wait().unwrap();
-			return Err(Error::NotFound);
+		// Connections are waited for by the supervisor, like any other service.
+		// One-time services have no process left once they finished, their pid is 0.
+		if service.control.one_time && ! connected {
+			let status = child.wait().unwrap();
+			if ! status.success() {
+				return Err(Error::Failed(status.to_string()));
+			}
+			return Ok(0);
 		}
 		return Ok(child.id() as i32);
 	}
@@ -271,46 +1167,249 @@ fn spawn_start(service: &Service) -> Result<i32, Error> {
 	Err(Error::CannotSpawn)
 }
 
+/// Start the service for the supervisor: at boot, on restart or on a trigger.
 pub fn start(service_name: &str, children: &Arc<Mutex<command::Children>>) -> Result<i32, Error> {
-	let service = load(service_name);
+	match load(service_name) {
+		Ok(service) => start_service(service_name, service, children, true),
+		Err(_) if masked(service_name) => Err(Error::Masked),
+		Err(_) => Err(Error::NotFound),
+	}
+}
 
-	if let Ok(service) = service {
-		let pid = spawn_start(&service);
+/// Start the service on request of `control start`, its failure action is not taken.
+pub fn start_requested(service_name: &str, children: &Arc<Mutex<command::Children>>) -> Result<i32, Error> {
+	match load(service_name) {
+		Ok(service) => start_service(service_name, service, children, false),
+		Err(_) if masked(service_name) => Err(Error::Masked),
+		Err(_) => Err(Error::NotFound),
+	}
+}
+
+fn start_service(service_name: &str, service: Service, children: &Arc<Mutex<command::Children>>,
+	failure_action: bool) -> Result<i32, Error> {
+	if masked(service_name) {
+		return Err(Error::Masked);
+	}
+
+	let _ = fs::remove_file(conditionfile(service_name));
 
-		if let Ok(pid) = pid {
-			pidfile_put(service_name, pid);
-			let mut children_ref = children.lock().unwrap();
-			let control = service.control;
-			let child = (service_name.into(), control.restart, control.restart_always);
-			children_ref.insert(pid, child);
+	// A failed condition skips the service, a failed assertion is an error.
+	for (checks, failed, error) in [
+		(&service.conditions, "Condition failed", Error::Condition),
+		(&service.assertions, "Assertion failed", Error::Assertion),
+	] {
+		if let Some(Err(check)) = checks.as_ref().map(|checks| checks.check()) {
+			let _ = fs::write(conditionfile(service_name), format!("{failed}: {check}\n"));
+			return Err(error);
 		}
+	}
 
-		return pid
+	stop_conflicts(service_name, &service, children);
+
+	let pid = spawn_start(service_name, &service, None);
+
+	if let Some(pid) = pid.as_ref().ok().copied().filter(|pid| *pid > 0) {
+		pidfile_put(service_name, pid);
+		let mut children_ref = children.lock().unwrap();
+		let child = command::Child {
+			service_name: service_name.into(),
+			restart: service.control.restart,
+			restart_always: service.control.restart_always,
+			restart_limit: service.control.restart_limit,
+			notify_all: service.control.notify_access == NotifyAccess::All,
+			connection: false,
+			unhealthy: false,
+		};
+		children_ref.insert(pid, child);
+		drop(children_ref);
+
+		if service.health.is_some() {
+			health::monitor(service_name, pid, children);
+		}
+
+		if let Some(watchdog_sec) = service.control.watchdog_sec {
+			notify::watchdog(service_name, pid, watchdog_sec, children);
+		}
 	}
 
-	Err(Error::NotFound)
+	if let Err(Error::Failed(status)) = &pid {
+		failed(service_name, status, children, failure_action);
+	}
+
+	pid
+}
+
+/// Stop the running services conflicting with the service, they are not restarted.
+fn stop_conflicts(service_name: &str, service: &Service, children: &Arc<Mutex<command::Children>>) {
+	let running: Vec<(i32, String)> = children.lock().unwrap().iter()
+		.filter(|(_, child)| ! child.connection && child.service_name != service_name)
+		.map(|(pid, child)| (*pid, child.service_name.clone()))
+		.collect();
+
+	for (pid, other_name) in running {
+		if ! conflicts(service_name, service, &other_name) {
+			continue;
+		}
+		let Ok(other) = load(&other_name) else {
+			continue;
+		};
+
+		if let Some(child) = children.lock().unwrap().get_mut(&pid) {
+			child.restart = false;
+			child.restart_always = false;
+			child.unhealthy = false;
+		}
+
+		table::log_bold("Stopping", &format!("{other_name} (conflicts with {service_name})"));
+		if stop(&other, Some(pid)).is_ok() && ! exited(pid, CONFLICT_TIMEOUT) {
+			table::log_bold("Still running", &format!("{other_name} (conflicts with {service_name})"));
+		}
+	}
+}
+
+/// Time given to a conflicting service to exit before the service is started anyway.
+const CONFLICT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wait for the process to exit within the timeout, whether it was reaped yet or not.
+fn exited(pid: i32, timeout: Duration) -> bool {
+	let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+	if fd < 0 {
+		return true;
+	}
+	let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+
+	let mut pollfd = libc::pollfd { fd: fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+	unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) > 0 }
+}
+
+thread_local! {
+	/// Failed services whose on-failure services are being started by this thread.
+	static FAILING: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+}
+
+/// The service failed for good: record why, start its on-failure services
+/// with FAILED_SERVICE set, then take its failure action if asked to.
+pub fn failed(service_name: &str, reason: &str, children: &Arc<Mutex<command::Children>>, failure_action: bool) {
+	let _ = fs::write(conditionfile(service_name), format!("Failed: {reason}\n"));
+	table::log_bold("Failed", &format!("{service_name} ({reason})"));
+
+	let Ok(service) = load(service_name) else {
+		return;
+	};
+
+	FAILING.with_borrow_mut(|failing| failing.push(service_name.into()));
+
+	for handler_name in &service.control.on_failure {
+		// One-time handlers failing in turn would start each other endlessly.
+		if FAILING.with_borrow(|failing| failing.contains(handler_name)) {
+			table::log_bold("Skipping failed", &format!("{handler_name} (on-failure of {service_name})"));
+			continue;
+		}
+
+		let Ok(mut handler) = load(handler_name) else {
+			continue;
+		};
+		handler.env.get_or_insert_with(HashMap::new).insert("FAILED_SERVICE".into(), service_name.into());
+		let _ = start_service(handler_name, handler, children, failure_action);
+	}
+
+	FAILING.with_borrow_mut(|failing| failing.pop());
+
+	if ! failure_action {
+		return;
+	}
+
+	// Definite stops the services before it reboots or powers off.
+	match service.control.failure_action {
+		FailureAction::None => {},
+		FailureAction::Reboot => { let _ = kill(1, libc::SIGUSR1); },
+		FailureAction::Poweroff => { let _ = kill(1, libc::SIGTERM); },
+		FailureAction::RebootForce => unsafe {
+			libc::sync();
+			libc::reboot(libc::LINUX_REBOOT_CMD_RESTART);
+		},
+	}
 }
 
-pub fn start_socket(service_name: &str) -> Result<(), Error> {
+/// Start an instance of the service handling the connection.
+pub fn start_connection(service_name: &str, connection: activation::Connection,
+	children: &Arc<Mutex<command::Children>>) -> Result<i32, Error> {
+	let Ok(service) = load(service_name) else {
+		return Err(Error::NotFound);
+	};
+
+	// Only registered processes are reaped, an instance exiting right away waits for it.
+	let pid = spawn_start(service_name, &service, Some(connection))?;
+	let mut children_ref = children.lock().unwrap();
+	let child = command::Child {
+		service_name: service_name.into(),
+		restart: false,
+		restart_always: false,
+		restart_limit: None,
+		notify_all: false,
+		connection: true,
+		unhealthy: false,
+	};
+	children_ref.insert(pid, child);
+
+	Ok(pid)
+}
+
+/// Ask the supervisor to start the service, the pid is 0 for a finished one-time service.
+pub fn start_socket(service_name: &str) -> Result<i32, Error> {
 	let cmdline = format!("start {service_name}");
 	let pid = socket::socket_chat(&cmdline);
 
 	if let Ok(pid) = pid {
-		if pid == 0 {
+		if pid < 0 {
 			return Err(Error::NotFound);
 		}
-		return Ok(());
+		return Ok(pid);
 	}
 
 	Err(Error::NoDaemon)
 }
 
-fn spawn(command: &[String]) -> Result<(), Error> {
-	let child = Command::new(&command[0]).args(&command[1..]).spawn();
-	if child.is_ok() {
-		Ok(())
-	} else {
-		Err(Error::CannotSpawn)
+fn spawn(service: &Service, command: &[String], pid: Option<i32>) -> Result<(), Error> {
+	let Ok(mut child) = service.command(command, pid)?.spawn() else {
+		return Err(Error::CannotSpawn);
+	};
+
+	// Only supervised processes are reaped by the supervisor.
+	thread::spawn(move || child.wait());
+	Ok(())
+}
+
+impl Service {
+	/// Run the health command of the service, it must succeed within the timeout.
+	pub fn check_health(&self, pid: i32) -> bool {
+		let Some(health) = &self.health else {
+			return true;
+		};
+
+		let Ok(mut command) = self.command(&health.cmd, Some(pid)) else {
+			return false;
+		};
+		command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).process_group(0);
+		let Ok(mut child) = command.spawn() else {
+			return false;
+		};
+
+		let timeout = Duration::from_secs(health.timeout.unwrap_or(30));
+		let started = Instant::now();
+
+		loop {
+			match child.try_wait() {
+				Ok(Some(status)) => return status.success(),
+				Ok(None) if started.elapsed() < timeout => thread::sleep(Duration::from_millis(50)),
+				_ => {
+					// The whole group, the command may have started its own processes.
+					let _ = kill(-(child.id() as i32), libc::SIGKILL);
+					let _ = child.wait();
+					return false;
+				},
+			}
+		}
 	}
 }
 
@@ -325,9 +1424,9 @@ fn kill(pid: i32, sig: i32) -> Result<(), Error> {
 
 pub fn stop(service: &Service, pid: Option<i32>) -> Result<(), Error> {
 	if let Some(stop_cmd) = &service.process.stop_cmd {
-		return spawn(stop_cmd);
+		return spawn(service, stop_cmd, pid);
 	} else if let Some(pid) = pid {
-		return kill(pid, service.process.stop_sig);
+		return kill(pid, service.process.stop_sig.0);
 	}
 
 	Err(Error::NotFound)
@@ -335,9 +1434,9 @@ pub fn stop(service: &Service, pid: Option<i32>) -> Result<(), Error> {
 
 pub fn restart(service: &Service, pid: i32) -> Result<(), Error> {
 	if let Some(restart_cmd) = &service.process.restart_cmd {
-		return spawn(restart_cmd);
+		return spawn(service, restart_cmd, Some(pid));
 	} else if let Some(restart_sig) = service.process.restart_sig {
-		return kill(pid, restart_sig);
+		return kill(pid, restart_sig.0);
 	}
 
 	Err(Error::NotFound)
@@ -345,8 +1444,8 @@ pub fn restart(service: &Service, pid: i32) -> Result<(), Error> {
 
 pub fn reload(service: &Service, pid: i32) -> Result<(), Error> {
 	if let Some(reload_cmd) = &service.process.reload_cmd {
-		spawn(reload_cmd)
+		spawn(service, reload_cmd, Some(pid))
 	} else {
-		kill(pid, service.process.reload_sig)
+		kill(pid, service.process.reload_sig.0)
 	}
 }
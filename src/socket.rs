@@ -14,7 +14,7 @@ fn start(service_name: &str, children: &Arc<Mutex<command::Children>>) -> Option
 	let pid = service::pidfile_get(service_name);
 
 	if pid.is_none() {
-		let pid = service::start(service_name, children);
+		let pid = service::start_requested(service_name, children);
 		return pid.ok();
 	}
 
@@ -26,7 +26,15 @@ fn stop(service_name: &str, children: &Arc<Mutex<command::Children>>) -> Option<
 
 	if let Some(pid) = pid {
 		let mut children_ref = children.lock().unwrap();
-		let child = (service_name.into(), false, false);
+		let child = command::Child {
+			service_name: service_name.into(),
+			restart: false,
+			restart_always: false,
+			restart_limit: None,
+			notify_all: false,
+			connection: false,
+			unhealthy: false,
+		};
 		children_ref.insert(pid, child);
 	}
 
@@ -61,7 +69,7 @@ pub fn listen(children: &Arc<Mutex<command::Children>>) {
 				&_ => None,
 			};
 
-			let pid = pid.unwrap_or(0).to_string();
+			let pid = pid.unwrap_or(-1).to_string();
 			socket.write_all(pid.as_bytes()).unwrap();
 		}
 	});
@@ -2,69 +2,208 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
 pub const RED: &str = "\u{1b}[1;31m";
 pub const GREEN: &str = "\u{1b}[1;32m";
 pub const YELLOW: &str = "\u{1b}[1;33m";
 pub const BOLD: &str = "\u{1b}[1;37m";
-const MUTED: &str = "\u{1b}[2;37m";
+pub const NORMAL: &str = "\u{1b}[0;37m";
+pub const MUTED: &str = "\u{1b}[2;37m";
 const RESET: &str = "\u{1b}[0m";
 
+/// Format of everything printed, set once by the --output option.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Output {
+	Table,
+	Plain,
+	Json,
+}
+
+impl FromStr for Output {
+	type Err = String;
+
+	fn from_str(output: &str) -> Result<Output, String> {
+		match output {
+			"table" => Ok(Output::Table),
+			"plain" => Ok(Output::Plain),
+			"json" => Ok(Output::Json),
+			_ => Err(format!("unknown output `{output}`, expected json, plain or table")),
+		}
+	}
+}
+
+/// When to colour the output, set once by the --color option.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Color {
+	Auto,
+	Always,
+	Never,
+}
+
+impl FromStr for Color {
+	type Err = String;
+
+	fn from_str(color: &str) -> Result<Color, String> {
+		match color {
+			"auto" => Ok(Color::Auto),
+			"always" => Ok(Color::Always),
+			"never" => Ok(Color::Never),
+			_ => Err(format!("unknown color `{color}`, expected auto, always or never")),
+		}
+	}
+}
+
+static OUTPUT: AtomicU8 = AtomicU8::new(Output::Table as u8);
+static COLOR: AtomicU8 = AtomicU8::new(Color::Auto as u8);
+
+pub fn set_output(output: Output) {
+	OUTPUT.store(output as u8, Ordering::Relaxed);
+}
+
+pub fn output() -> Output {
+	match OUTPUT.load(Ordering::Relaxed) {
+		1 => Output::Plain,
+		2 => Output::Json,
+		_ => Output::Table,
+	}
+}
+
+pub fn set_color(color: Color) {
+	COLOR.store(color as u8, Ordering::Relaxed);
+}
+
+fn stdout_is_tty() -> bool {
+	unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+/// Colours are used by the table output, on terminals, unless NO_COLOR is set.
+fn colored() -> bool {
+	match COLOR.load(Ordering::Relaxed) {
+		1 => output() == Output::Table,
+		2 => false,
+		_ => {
+			let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| ! value.is_empty());
+			output() == Output::Table && ! no_color && stdout_is_tty()
+		},
+	}
+}
+
+/// Width of the terminal, tables are not limited when it is unknown.
+fn terminal_width() -> Option<usize> {
+	let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+	let err = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+
+	if err == 0 && size.ws_col > 0 {
+		return Some(size.ws_col as usize);
+	}
+
+	std::env::var("COLUMNS").ok().and_then(|columns| columns.parse().ok())
+}
+
+/// Columns taken by the text on a terminal.
+pub fn width(txt: &str) -> usize {
+	UnicodeWidthStr::width(txt)
+}
+
+/// Pad the text with spaces up to the width.
+pub fn pad(txt: &str, len: usize) -> String {
+	format!("{txt}{}", " ".repeat(len.saturating_sub(width(txt))))
+}
+
+fn json_string(txt: &str) -> String {
+	let mut json = String::from('"');
+	for c in txt.chars() {
+		match c {
+			'"' => json.push_str("\\\""),
+			'\\' => json.push_str("\\\\"),
+			'\n' => json.push_str("\\n"),
+			'\t' => json.push_str("\\t"),
+			c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+			c => json.push(c),
+		}
+	}
+	json.push('"');
+	json
+}
+
+/// Cells are made of coloured parts, only one except for `ppfirst`.
+type Cell = Vec<(String, &'static str)>;
+
 pub struct Table {
 	i: usize,
-	j: usize,
-	tab: Vec<Vec<String>>,
-	col: Vec<usize>,
-	mult: usize,
+	tab: Vec<Vec<Cell>>,
+	keys: Vec<&'static str>,
+	values: Vec<Vec<String>>,
+	errors: Vec<Option<String>>,
+	details: Vec<Vec<(String, String)>>,
 }
 
 impl Table {
 	pub fn new() -> Table {
 		Table {
 			i: 0,
-			j: 0,
 			tab: vec![],
-			col: vec![],
-			mult: 1,
+			keys: vec![],
+			values: vec![],
+			errors: vec![],
+			details: vec![],
 		}
 	}
 
+	/// Names of the columns, used as keys by the json output.
+	pub fn keys(mut self, keys: &[&'static str]) -> Table {
+		self.keys = keys.to_vec();
+		self
+	}
+
 	fn row(&mut self) -> &mut Table {
 		self.i += 1;
-		self.j = 0;
 		self.tab.push(vec![]);
+		self.values.push(vec![]);
+		self.errors.push(None);
+		self.details.push(vec![]);
 		self
 	}
 
-	fn col(&mut self, len: usize) {
-		if self.col.len() <= self.j {
-			self.col.push(0);
-		}
-		if self.col[self.j] < len {
-			self.col[self.j] = len;
-		}
-		self.j += 1;
+	pub fn field(&mut self, txt: &str, color: &'static str) -> &mut Table {
+		self.values[self.i-1].push(txt.into());
+		self.tab[self.i-1].push(vec![(txt.into(), color)]);
+		self
 	}
 
-	fn txt(&mut self, txt: String) {
-		self.tab[self.i-1].push(txt);
+	/// A red field, which is the error of the row in the json output.
+	pub fn error(&mut self, txt: &str) -> &mut Table {
+		self.errors[self.i-1].get_or_insert(txt.into());
+		self.field(txt, RED)
 	}
 
-	pub fn field(&mut self, txt: &str, color: &str) -> &mut Table {
-		self.txt(format!("{color}{txt}{RESET}"));
-		self.col(txt.len());
+	/// Properties of the row, a nested "details" object in the json output.
+	pub fn details(&mut self, details: Vec<(String, String)>) -> &mut Table {
+		self.details[self.i-1] = details;
 		self
 	}
 
+	/// The command failed when any of the rows has an error.
+	pub fn status(&self) -> ExitCode {
+		match self.errors.iter().any(Option::is_some) {
+			true => ExitCode::FAILURE,
+			false => ExitCode::SUCCESS,
+		}
+	}
+
 	pub fn first(&mut self, txt: &str) -> &mut Table {
 		self.row();
 		self.field(txt, BOLD)
 	}
 
 	pub fn ppfirst(&mut self, pre: &str, txt: &str, post: &str) -> &mut Table {
-		self.mult = 3;
 		self.row();
-		self.txt(format!("{MUTED}{pre}{RESET}{BOLD}{txt}{RESET}{MUTED}{post}{RESET}"));
-		self.col(pre.len() + txt.len() + post.len());
+		self.values[self.i-1].push(format!("{pre}{txt}{post}"));
+		self.tab[self.i-1].push(vec![(pre.into(), MUTED), (txt.into(), BOLD), (post.into(), MUTED)]);
 		self
 	}
 
@@ -75,32 +214,169 @@ impl Table {
 		self
 	}
 
+	fn key(&self, j: usize) -> String {
+		match self.keys.get(j) {
+			Some(key) => key.to_string(),
+			None => format!("field{j}"),
+		}
+	}
+
+	fn print_json(&self) {
+		let object = |pairs: Vec<String>| format!("{{{}}}", pairs.join(","));
+
+		for ((row, error), details) in self.values.iter().zip(&self.errors).zip(&self.details) {
+			let mut pairs: Vec<String> = row.iter().enumerate()
+				.filter(|(_, value)| ! value.is_empty())
+				.map(|(j, value)| format!("{}:{}", json_string(&self.key(j)), json_string(value)))
+				.collect();
+			let error = error.as_deref().map(json_string).unwrap_or("null".into());
+			pairs.push(format!("\"error\":{error}"));
+			if ! details.is_empty() {
+				let details = details.iter().map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)));
+				pairs.push(format!("\"details\":{}", object(details.collect())));
+			}
+			println!("{}", object(pairs));
+		}
+	}
+
+	/// Widths of the columns, shrinking the widest ones to fit the terminal.
+	fn widths(&self) -> Vec<usize> {
+		let mut widths: Vec<usize> = vec![];
+
+		for row in &self.tab {
+			for (j, cell) in row.iter().enumerate() {
+				let len = cell.iter().map(|(txt, _)| width(txt)).sum();
+				match widths.get_mut(j) {
+					Some(width) => *width = (*width).max(len),
+					None => widths.push(len),
+				}
+			}
+		}
+
+		// Two spaces of indent, then " | " between columns.
+		if let Some(max) = terminal_width().filter(|_| stdout_is_tty()) {
+			let total = |widths: &[usize]| 2 + widths.iter().sum::<usize>() + 3 * widths.len().saturating_sub(1);
+			while total(&widths) > max {
+				let widest = widths.iter_mut().max().unwrap();
+				if *widest <= 8 {
+					break;
+				}
+				*widest -= 1;
+			}
+		}
+
+		widths
+	}
+
 	pub fn print(&self) {
+		match output() {
+			Output::Json => return self.print_json(),
+			Output::Plain => {
+				for row in &self.values {
+					println!("{}", row.join("\t"));
+				}
+				return;
+			},
+			Output::Table => {},
+		}
+
+		let widths = self.widths();
 		let mut table = String::from('\n');
 
 		for row in &self.tab {
-			let indent = self.col[0] + 11 * self.mult;
-			let line = format!("  {:<indent$}", row[0]);
-			table.push_str(&line);
-
-			for (j, txt) in row.iter().enumerate().skip(1) {
-				let indent = self.col[j] + 11;
-				let line = format!(" {MUTED}|{RESET} {:<indent$}", txt);
-				table.push_str(&line);
-			}
+			let cells: Vec<Vec<Cell>> = row.iter().enumerate().map(|(j, cell)| wrap(cell, widths[j])).collect();
+			let height = cells.iter().map(Vec::len).max().unwrap_or(0);
+
+			// Cells too wide for their column go on as many lines as needed.
+			for line in 0 .. height {
+				table.push_str("  ");
+
+				for (j, cell) in cells.iter().enumerate() {
+					if j > 0 {
+						table.push_str(&format!(" {} ", paint("|", MUTED)));
+					}
+
+					let parts = cell.get(line).map(Vec::as_slice).unwrap_or(&[]);
+					let mut room = widths[j];
+					for (txt, color) in parts {
+						room = room.saturating_sub(width(txt));
+						table.push_str(&paint(txt, color));
+					}
+
+					// The last column is not padded, trailing spaces would wrap narrow terminals.
+					if j + 1 < row.len() {
+						table.push_str(&" ".repeat(room));
+					}
+				}
 
-			table.push('\n');
+				table.push('\n');
+			}
 		}
 
 		println!("{table}");
 	}
 }
 
-pub fn table_err(first: &str, txt: &str) {
-	let mut table = Table::new();
-	table.first(first).field(txt, RED).print();
+/// Lines of the cell wrapped to the width, at spaces when there are some.
+fn wrap(cell: &Cell, len: usize) -> Vec<Cell> {
+	let mut lines: Vec<Vec<(char, &'static str)>> = vec![vec![]];
+
+	for (txt, color) in cell {
+		for c in txt.chars() {
+			let line = lines.last_mut().unwrap();
+			let used: usize = line.iter().map(|(c, _)| c.width().unwrap_or(0)).sum();
+
+			if used + c.width().unwrap_or(0) > len && ! line.is_empty() {
+				let next = match line.iter().rposition(|(c, _)| *c == ' ') {
+					Some(space) if space > 0 => line.split_off(space + 1),
+					_ => vec![],
+				};
+				while line.last().is_some_and(|(c, _)| *c == ' ') {
+					line.pop();
+				}
+				lines.push(next);
+			}
+
+			// Wrapped lines do not start with spaces.
+			let wrapped = lines.len() > 1;
+			let line = lines.last_mut().unwrap();
+			if c == ' ' && line.is_empty() && wrapped {
+				continue;
+			}
+			line.push((c, color));
+		}
+	}
+
+	// Back to parts, one per run of the same colour.
+	lines.into_iter().map(|line| {
+		let mut parts: Cell = vec![];
+		for (c, color) in line {
+			match parts.last_mut() {
+				Some((txt, last)) if *last == color => txt.push(c),
+				_ => parts.push((c.into(), color)),
+			}
+		}
+		parts
+	}).collect()
+}
+
+pub fn table_err(first: &str, txt: &str) -> ExitCode {
+	let mut table = Table::new().keys(&["service", "state"]);
+	table.first(first).error(txt).print();
+	ExitCode::FAILURE
 }
 
 pub fn log_bold(first: &str, txt: &str) {
-	println!("{MUTED}{first}{RESET} {BOLD}{txt}{RESET} {MUTED}...{RESET}");
+	match output() {
+		Output::Table => println!("{} {} {}", paint(first, MUTED), paint(txt, BOLD), paint("...", MUTED)),
+		Output::Plain => println!("{first}\t{txt}"),
+		Output::Json => println!("{{\"event\":{},\"service\":{}}}", json_string(first), json_string(txt)),
+	}
+}
+
+pub fn paint(txt: &str, color: &str) -> String {
+	match colored() {
+		true => format!("{color}{txt}{RESET}"),
+		false => txt.into(),
+	}
 }
@@ -0,0 +1,436 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::{command, service};
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fmt, thread};
+use serde::{de, Deserialize, Deserializer};
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Timer {
+	#[serde(alias="on-calendar", default, deserialize_with="calendar")]
+	on_calendar: Option<Calendar>,
+	#[serde(alias="on-boot-sec", default, deserialize_with="seconds")]
+	on_boot_sec: Option<u64>,
+	#[serde(alias="on-unit-active-sec", default, deserialize_with="seconds")]
+	on_unit_active_sec: Option<u64>,
+	#[serde(alias="randomized-delay-sec", default, deserialize_with="seconds")]
+	randomized_delay_sec: Option<u64>,
+	#[serde(default="service::xfalse")]
+	persistent: bool,
+}
+
+/// Values allowed for a field of a calendar event, an empty list allows any value.
+#[derive(Debug, Default)]
+struct Field(Vec<(u32, u32, u32)>);
+
+impl Field {
+	/// Parse "*", "5", "1,15", "1..5", "*/10" or "5/15".
+	fn parse(spec: &str, min: u32, max: u32) -> Option<Field> {
+		if spec == "*" {
+			return Some(Field::default());
+		}
+
+		let mut ranges = vec![];
+
+		for item in spec.split(',') {
+			let (range, step) = match item.split_once('/') {
+				Some((range, step)) => (range, step.parse().ok().filter(|step| *step > 0)?),
+				None => (item, 1),
+			};
+
+			let (first, last) = match range.split_once("..") {
+				Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+				None if range == "*" => (min, max),
+				None if step > 1 => (range.parse().ok()?, max),
+				None => (range.parse().ok()?, range.parse().ok()?),
+			};
+
+			if first < min || last > max || first > last {
+				return None;
+			}
+
+			ranges.push((first, last, step));
+		}
+
+		Some(Field(ranges))
+	}
+
+	fn matches(&self, value: u32) -> bool {
+		self.0.is_empty() || self.0.iter()
+			.any(|(first, last, step)| value >= *first && value <= *last && (value - first).is_multiple_of(*step))
+	}
+}
+
+/// A calendar event, like "Mon..Fri *-*-* 03:00:00", in local time.
+#[derive(Debug, Default)]
+pub struct Calendar {
+	spec: String,
+	weekdays: Field,
+	year: Field,
+	month: Field,
+	day: Field,
+	hour: Field,
+	minute: Field,
+	second: Field,
+}
+
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl Calendar {
+	fn parse(spec: &str) -> Option<Calendar> {
+		let mut calendar = Calendar { spec: spec.into(), ..Calendar::default() };
+
+		let spec = match spec {
+			"minutely" => "*-*-* *:*:00",
+			"hourly" => "*-*-* *:00:00",
+			"daily" => "*-*-* 00:00:00",
+			"weekly" => "Mon *-*-* 00:00:00",
+			"monthly" => "*-*-01 00:00:00",
+			"yearly" | "annually" => "*-01-01 00:00:00",
+			spec => spec,
+		};
+
+		let mut tokens = spec.split_whitespace().peekable();
+
+		if let Some(weekdays) = tokens.next_if(|token| token.starts_with(char::is_alphabetic)) {
+			let weekdays = weekdays.to_ascii_lowercase();
+			let mut numbers = weekdays.clone();
+			for (number, name) in WEEKDAYS.iter().enumerate() {
+				numbers = numbers.replace(name, &number.to_string());
+			}
+			calendar.weekdays = Field::parse(&numbers, 0, 6)?;
+		}
+
+		let date = tokens.next_if(|token| token.contains('-')).unwrap_or("*-*-*");
+		let date: Vec<&str> = date.split('-').collect();
+		let (year, month, day) = match date[..] {
+			[year, month, day] => (year, month, day),
+			[month, day] => ("*", month, day),
+			_ => return None,
+		};
+		calendar.year = Field::parse(year, 1970, 2199)?;
+		calendar.month = Field::parse(month, 1, 12)?;
+		calendar.day = Field::parse(day, 1, 31)?;
+
+		let time = tokens.next().unwrap_or("00:00:00");
+		let time: Vec<&str> = time.split(':').collect();
+		let (hour, minute, second) = match time[..] {
+			[hour, minute, second] => (hour, minute, second),
+			[hour, minute] => (hour, minute, "0"),
+			_ => return None,
+		};
+		calendar.hour = Field::parse(hour, 0, 23)?;
+		calendar.minute = Field::parse(minute, 0, 59)?;
+		calendar.second = Field::parse(second, 0, 59)?;
+
+		match tokens.next() {
+			Some(_) => None,
+			None => Some(calendar),
+		}
+	}
+
+	/// The first time matching the event after the time.
+	fn next(&self, after: u64) -> Option<u64> {
+		let mut time = after + 1;
+
+		for _ in 0 .. 100_000 {
+			let tm = localtime(time);
+			let (year, month) = (tm.tm_year + 1900, tm.tm_mon);
+
+			// Skip to the start of the next year, month, day, hour or minute.
+			let next = if ! self.year.matches(year as u32) {
+				if year >= 2199 { return None; }
+				mktime(year + 1, 0, 1, 0, 0, 0)
+			} else if ! self.month.matches(month as u32 + 1) {
+				mktime(year, month + 1, 1, 0, 0, 0)
+			} else if ! self.day.matches(tm.tm_mday as u32) || ! self.weekdays.matches(tm.tm_wday as u32) {
+				mktime(year, month, tm.tm_mday + 1, 0, 0, 0)
+			} else if ! self.hour.matches(tm.tm_hour as u32) {
+				mktime(year, month, tm.tm_mday, tm.tm_hour + 1, 0, 0)
+			} else if ! self.minute.matches(tm.tm_min as u32) {
+				mktime(year, month, tm.tm_mday, tm.tm_hour, tm.tm_min + 1, 0)
+			} else if ! self.second.matches(tm.tm_sec as u32) {
+				time + 1
+			} else {
+				return Some(time);
+			};
+
+			// Days skipped by daylight saving time changes.
+			time = next.max(time + 1);
+		}
+
+		None
+	}
+}
+
+fn localtime(time: u64) -> libc::tm {
+	let time = time as libc::time_t;
+	unsafe {
+		let mut tm: libc::tm = std::mem::zeroed();
+		libc::localtime_r(&time, &mut tm);
+		tm
+	}
+}
+
+fn mktime(year: i32, month: i32, day: i32, hour: i32, minute: i32, second: i32) -> u64 {
+	unsafe {
+		let mut tm: libc::tm = std::mem::zeroed();
+		tm.tm_year = year - 1900;
+		tm.tm_mon = month;
+		tm.tm_mday = day;
+		tm.tm_hour = hour;
+		tm.tm_min = minute;
+		tm.tm_sec = second;
+		tm.tm_isdst = -1;
+		libc::mktime(&mut tm).max(0) as u64
+	}
+}
+
+fn calendar<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Calendar>, D::Error> {
+	let spec = String::deserialize(deserializer)?;
+	match Calendar::parse(&spec) {
+		Some(calendar) => Ok(Some(calendar)),
+		None => Err(de::Error::custom(format!("invalid calendar event `{spec}`"))),
+	}
+}
+
+/// Parse a time span like "90", "90s", "5min" or "1h 30min", in seconds.
+fn parse_seconds(span: &str) -> Option<u64> {
+	let mut seconds = 0;
+	let mut rest = span.trim();
+
+	if rest.is_empty() {
+		return None;
+	}
+
+	while ! rest.is_empty() {
+		let digits = rest.find(|c: char| ! c.is_ascii_digit()).unwrap_or(rest.len());
+		let number: u64 = rest[.. digits].parse().ok()?;
+		rest = rest[digits ..].trim_start();
+
+		let unit = rest.find(|c: char| ! c.is_ascii_alphabetic()).unwrap_or(rest.len());
+		let factor = match &rest[.. unit] {
+			"" | "s" | "sec" | "second" | "seconds" => 1,
+			"m" | "min" | "minute" | "minutes" => 60,
+			"h" | "hr" | "hour" | "hours" => 3600,
+			"d" | "day" | "days" => 86400,
+			"w" | "week" | "weeks" => 604800,
+			_ => return None,
+		};
+		rest = rest[unit ..].trim_start();
+
+		seconds += number * factor;
+	}
+
+	Some(seconds)
+}
+
+struct SecondsVisitor;
+
+impl<'de> de::Visitor<'de> for SecondsVisitor {
+	type Value = Option<u64>;
+
+	fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "a number of seconds or a time span like \"1h 30min\"")
+	}
+
+	fn visit_i64<E: de::Error>(self, seconds: i64) -> Result<Option<u64>, E> {
+		u64::try_from(seconds).map(Some).map_err(|_| E::custom("expected a positive number of seconds"))
+	}
+
+	fn visit_str<E: de::Error>(self, span: &str) -> Result<Option<u64>, E> {
+		parse_seconds(span).map(Some).ok_or_else(|| E::custom(format!("invalid time span `{span}`")))
+	}
+}
+
+pub fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+	deserializer.deserialize_any(SecondsVisitor)
+}
+
+pub fn now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+fn random(max: u64) -> u64 {
+	let mut random = [0u8; 8];
+	unsafe { libc::getrandom(random.as_mut_ptr() as *mut libc::c_void, random.len(), 0) };
+	u64::from_ne_bytes(random) % (max + 1)
+}
+
+/// Time of the last run of the service, kept across reboots.
+pub fn stamp(service_name: &str) -> Option<u64> {
+	let stamp = fs::read_to_string(service::timer_stamp(service_name)).ok()?;
+	stamp.trim().parse().ok()
+}
+
+fn stamp_put(service_name: &str, time: u64) {
+	let stampfile = service::timer_stamp(service_name);
+	if let Some(dir) = std::path::Path::new(&stampfile).parent() {
+		let _ = fs::create_dir_all(dir);
+	}
+	let _ = fs::write(stampfile, format!("{time}\n"));
+}
+
+/// Next elapse of the timers, written by the supervisor for `control timers`.
+pub fn elapses() -> Vec<(String, u64)> {
+	let text = fs::read_to_string(service::timersfile()).unwrap_or_default();
+	text.lines().filter_map(|line| {
+		let (service_name, next) = line.split_once(' ')?;
+		Some((service_name.into(), next.parse().ok()?))
+	}).collect()
+}
+
+impl Timer {
+	/// Timers without any trigger never elapse.
+	pub fn elapses(&self) -> bool {
+		self.on_calendar.is_some() || self.on_boot_sec.is_some() || self.on_unit_active_sec.is_some()
+	}
+
+	/// Details shown by `control status --verbose`.
+	pub fn properties(&self) -> Vec<(&'static str, String)> {
+		let mut properties = vec![];
+
+		if let Some(calendar) = &self.on_calendar {
+			properties.push(("On calendar", calendar.spec.clone()));
+		}
+
+		for (key, seconds) in [
+			("On boot", self.on_boot_sec),
+			("On unit active", self.on_unit_active_sec),
+			("Randomized delay", self.randomized_delay_sec),
+		] {
+			if let Some(seconds) = seconds {
+				properties.push((key, format_span(seconds)));
+			}
+		}
+
+		if self.persistent {
+			properties.push(("Persistent", "yes".into()));
+		}
+
+		properties
+	}
+}
+
+struct Schedule {
+	service_name: String,
+	timer: Timer,
+	boot_done: bool,
+	last: Option<u64>,
+	next: Option<u64>,
+	run: Option<thread::JoinHandle<()>>,
+}
+
+impl Schedule {
+	/// The next elapse, randomly delayed, or None if the timer won't elapse anymore.
+	fn next(&self, boot: u64, now: u64) -> Option<u64> {
+		let timer = &self.timer;
+		let mut elapses = vec![];
+
+		if let Some(calendar) = &timer.on_calendar {
+			// Runs missed while the supervisor was not running are caught up at once.
+			let last = stamp(&self.service_name).filter(|_| timer.persistent);
+			match last.and_then(|last| calendar.next(last)) {
+				Some(missed) if missed <= now => elapses.push(now),
+				_ => elapses.extend(calendar.next(now)),
+			}
+		}
+
+		if let Some(on_boot_sec) = timer.on_boot_sec.filter(|_| ! self.boot_done) {
+			elapses.push(boot + on_boot_sec);
+		}
+
+		if let Some(on_unit_active_sec) = timer.on_unit_active_sec {
+			elapses.push(self.last.unwrap_or(boot) + on_unit_active_sec);
+		}
+
+		let delay = timer.randomized_delay_sec.map(random).unwrap_or(0);
+		elapses.into_iter().min().map(|next| next + delay)
+	}
+}
+
+/// Start the services of the timers when they elapse, from a thread of the supervisor.
+pub fn run(service_names: Vec<String>, children: &Arc<Mutex<command::Children>>) {
+	let children = Arc::clone(children);
+	let boot = now();
+
+	let mut schedules: Vec<Schedule> = service_names.into_iter().filter_map(|service_name| {
+		let timer = service::load(&service_name).ok()?.timer?;
+		Some(Schedule { service_name, timer, boot_done: false, last: None, next: None, run: None })
+	}).collect();
+
+	if schedules.is_empty() {
+		return;
+	}
+
+	thread::spawn(move || loop {
+		let now = now();
+		let mut elapses = String::new();
+
+		for schedule in &mut schedules {
+			if schedule.next.is_none() {
+				schedule.next = schedule.next(boot, now);
+			}
+
+			let Some(next) = schedule.next else {
+				continue;
+			};
+
+			// A run still going on delays the next one.
+			let running = schedule.run.as_ref().is_some_and(|run| ! run.is_finished());
+
+			if next <= now && ! running {
+				schedule.boot_done |= schedule.timer.on_boot_sec.is_some_and(|sec| boot + sec <= now);
+				schedule.last = Some(now);
+				stamp_put(&schedule.service_name, now);
+
+				// One-time services run until they exit, without holding the other timers.
+				let service_name = schedule.service_name.clone();
+				let children = Arc::clone(&children);
+				schedule.run = Some(thread::spawn(move || {
+					if service::pidfile_get(&service_name).is_none() {
+						let _ = service::start(&service_name, &children);
+					}
+				}));
+
+				schedule.next = schedule.next(boot, now);
+			}
+
+			if let Some(next) = schedule.next {
+				elapses.push_str(&format!("{} {next}\n", schedule.service_name));
+			}
+		}
+
+		let _ = fs::write(service::timersfile(), elapses);
+		thread::sleep(Duration::from_secs(1));
+	});
+}
+
+/// Format a time like "2024-01-31 03:00:00", in local time.
+pub fn format_time(time: u64) -> String {
+	let tm = localtime(time);
+	format!("{}-{:02}-{:02} {:02}:{:02}:{:02}", tm.tm_year + 1900, tm.tm_mon + 1,
+		tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+/// Format a time span like "2h 5min" or "30s", with its two largest units.
+pub fn format_span(seconds: u64) -> String {
+	let units = [
+		(seconds / 86400, "d"),
+		(seconds % 86400 / 3600, "h"),
+		(seconds % 3600 / 60, "min"),
+		(seconds % 60, "s"),
+	];
+
+	let first = units.iter().position(|(count, _)| *count > 0).unwrap_or(3);
+	let spans: Vec<String> = units[first ..].iter().take(2)
+		.filter(|(count, _)| *count > 0 || first == 3)
+		.map(|(count, unit)| format!("{count}{unit}"))
+		.collect();
+	spans.join(" ")
+}
@@ -0,0 +1,127 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::service;
+use std::fs;
+use std::io::Write;
+
+/// Timestamps of a service recorded by `control start-all`, in microseconds
+/// since the supervisor started.
+///
+/// Services with `notify-ready` are ready once they sent READY=1, one-time
+/// services once they exited, and the other services once spawned.
+pub struct Timing {
+	pub service_name: String,
+	pub spawn: u64,
+	pub ready: Option<u64>,
+}
+
+impl Timing {
+	pub fn duration(&self) -> Option<u64> {
+		self.ready.map(|ready| ready - self.spawn)
+	}
+}
+
+/// Microseconds on the monotonic clock.
+pub fn now() -> u64 {
+	let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+	unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+	time.tv_sec as u64 * 1_000_000 + time.tv_nsec as u64 / 1_000
+}
+
+/// Start a new record, the timestamps of the previous boot are lost.
+pub fn reset() {
+	let _ = fs::write(service::timingfile(), format!("start-all - {}\n", now()));
+}
+
+/// Append the event of the service to the record.
+pub fn record(event: &str, service_name: &str) {
+	let file = fs::OpenOptions::new().append(true).open(service::timingfile());
+	if let Ok(mut file) = file {
+		let _ = file.write_all(format!("{event} {service_name} {}\n", now()).as_bytes());
+	}
+}
+
+/// Timings of the services, in the order they were spawned.
+pub fn load() -> Vec<Timing> {
+	let text = fs::read_to_string(service::timingfile()).unwrap_or_default();
+	let mut start = 0;
+	let mut timings: Vec<Timing> = vec![];
+
+	for line in text.lines() {
+		let mut fields = line.split(' ');
+		let (Some(event), Some(service_name), Some(Ok(time))) =
+			(fields.next(), fields.next(), fields.next().map(str::parse::<u64>)) else {
+			continue;
+		};
+
+		let timing = timings.iter_mut().find(|timing| timing.service_name == service_name);
+
+		match (event, timing) {
+			("start-all", _) => start = time,
+			("spawn", None) => timings.push(Timing {
+				service_name: service_name.into(),
+				spawn: time.saturating_sub(start),
+				ready: None,
+			}),
+			("ready", Some(timing)) if timing.ready.is_none() => {
+				timing.ready = Some(time.saturating_sub(start));
+			},
+			_ => {},
+		}
+	}
+
+	timings
+}
+
+/// Format microseconds like "1.234s" or "56ms".
+pub fn format(time: u64) -> String {
+	match time {
+		0 ..= 999_999 => format!("{}ms", time / 1_000),
+		_ => format!("{}.{:03}s", time / 1_000_000, time / 1_000 % 1_000),
+	}
+}
+
+/// Timeline of the services as an SVG image, one bar per service.
+pub fn svg(timings: &[Timing]) -> String {
+	const WIDTH: u64 = 1000;
+	const ROW: u64 = 20;
+	const LABELS: u64 = 300;
+
+	let end = timings.iter().map(|timing| timing.ready.unwrap_or(timing.spawn)).max().unwrap_or(0);
+	let scale = |time: u64| time * WIDTH / end.max(1);
+	let height = ROW * (timings.len() as u64 + 2);
+
+	let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{height}\" \
+		font-family=\"monospace\" font-size=\"12\">\n", WIDTH + LABELS);
+	svg.push_str("<rect width=\"100%\" height=\"100%\" fill=\"#ffffff\"/>\n");
+
+	// A tick per second, or per 100ms for short boots.
+	let tick = if end > 10_000_000 { 1_000_000 } else { 100_000 };
+	for time in (0 ..= end).step_by(tick as usize) {
+		let x = scale(time);
+		svg.push_str(&format!("<line x1=\"{x}\" y1=\"0\" x2=\"{x}\" y2=\"{height}\" stroke=\"#eeeeee\"/>\n"));
+		svg.push_str(&format!("<text x=\"{}\" y=\"{}\" fill=\"#888888\">{}</text>\n",
+			x + 2, height - 4, format(time)));
+	}
+
+	for (row, timing) in timings.iter().enumerate() {
+		let y = ROW * (row as u64 + 1);
+		let x = scale(timing.spawn);
+		let (width, color) = match timing.ready {
+			Some(ready) => (scale(ready) - x, "#4a90d9"),
+			None => (scale(end) - x, "#d94a4a"),
+		};
+		let duration = timing.duration().map(format).unwrap_or("not ready".into());
+
+		svg.push_str(&format!("<rect x=\"{x}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{color}\"/>\n",
+			y + 2, width.max(1), ROW - 4));
+		let name = timing.service_name.replace('&', "&amp;").replace('<', "&lt;");
+		svg.push_str(&format!("<text x=\"{}\" y=\"{}\">{name} ({duration})</text>\n",
+			x + width.max(1) + 4, y + ROW - 6));
+	}
+
+	svg.push_str("</svg>\n");
+	svg
+}
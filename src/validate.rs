@@ -0,0 +1,207 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::service;
+use std::fmt;
+
+/// A problem found in a service file.
+pub struct Diagnostic {
+	pub file: String,
+	pub line: usize,
+	pub col: usize,
+	pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}:{}:{}: {}", self.file, self.line, self.col, self.message)
+	}
+}
+
+/// Keys are compared the way serde aliases accept them, "start_cmd" is "start-cmd".
+fn same_key(a: &str, b: &str) -> bool {
+	a.replace('_', "-") == b.replace('_', "-")
+}
+
+/// Offsets of the "[section]" headers and "key =" lines of a service file,
+/// along with the dotted path they define.
+fn keys(text: &str) -> Vec<(String, usize)> {
+	let unquote = |key: &str| key.trim().trim_matches(|c| c == '"' || c == '\'').to_string();
+	let mut keys = vec![];
+	let mut section = String::new();
+	let mut offset = 0;
+
+	for line in text.split_inclusive('\n') {
+		let trimmed = line.trim_start();
+		let start = offset + line.len() - trimmed.len();
+		offset += line.len();
+
+		if let Some(header) = trimmed.strip_prefix('[') {
+			if let Some((header, _)) = header.split_once(']') {
+				section = unquote(header);
+				keys.push((section.clone(), start));
+			}
+		} else if let Some((key, _)) = trimmed.split_once('=') {
+			let key = unquote(key);
+			let bare = key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+			if ! key.is_empty() && bare {
+				let path = if section.is_empty() { key } else { format!("{section}.{key}") };
+				keys.push((path, start));
+			}
+		}
+	}
+
+	keys
+}
+
+/// Offset of the key, or of its section if the key is missing.
+fn locate(text: &str, path: &str) -> usize {
+	let keys = keys(text);
+	let section = path.split('.').next().unwrap_or("");
+
+	let found = keys.iter().find(|(key, _)| same_key(key, path))
+		.or_else(|| keys.iter().find(|(key, _)| same_key(key, section)));
+
+	found.map(|(_, offset)| *offset).unwrap_or(0)
+}
+
+/// The key defined at the offset.
+fn path_at(text: &str, offset: usize) -> String {
+	let keys = keys(text);
+	let found = keys.iter().take_while(|(_, start)| *start <= offset).last();
+	found.map(|(key, _)| key.clone()).unwrap_or_default()
+}
+
+fn diagnostic(file: &str, text: &str, offset: usize, message: String) -> Diagnostic {
+	let before = &text[.. offset.min(text.len())];
+	let line = before.matches('\n').count() + 1;
+	let col = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+	Diagnostic { file: file.into(), line, col, message }
+}
+
+fn distance(a: &str, b: &str) -> usize {
+	let b: Vec<char> = b.chars().collect();
+	let mut row: Vec<usize> = (0 ..= b.len()).collect();
+
+	for (i, ca) in a.chars().enumerate() {
+		let mut diagonal = row[0];
+		row[0] = i + 1;
+		for (j, cb) in b.iter().enumerate() {
+			let cost = if ca == *cb { diagonal } else { diagonal + 1 };
+			diagonal = row[j + 1];
+			row[j + 1] = cost.min(row[j] + 1).min(row[j + 1] + 1);
+		}
+	}
+
+	row[b.len()]
+}
+
+/// Replace the list of expected names of unknown field and variant errors
+/// by the closest one.
+fn suggest(message: &str) -> String {
+	if ! message.starts_with("unknown field") && ! message.starts_with("unknown variant") {
+		return message.into();
+	}
+
+	let mut names = message.split('`').skip(1).step_by(2);
+	let Some(unknown) = names.next() else {
+		return message.into();
+	};
+
+	// Fields are also accepted in snake case, only suggest the documented kebab case.
+	let unknown = unknown.replace('_', "-");
+	let closest = names.filter(|name| ! name.contains('_'))
+		.map(|name| (distance(&unknown, name), name))
+		.min();
+
+	match closest {
+		Some((distance, name)) if distance <= 2.max(unknown.len() / 3) => {
+			let (head, _) = message.split_once(", expected").unwrap_or((message, ""));
+			format!("{head}, did you mean `{name}`?")
+		},
+		_ => message.into(),
+	}
+}
+
+/// Check the service files of the service, every problem is reported with its location.
+pub fn check(service_name: &str) -> Vec<Diagnostic> {
+	let files: Vec<(String, String)> = service::service_files(service_name).into_iter()
+		.map(|file| {
+			let text = service::read(service_name, &file);
+			(file, text)
+		})
+		.collect();
+
+	let text_of = |file: &str| {
+		files.iter().find(|(name, _)| name == file).map(|(_, text)| text.as_str()).unwrap_or("")
+	};
+
+	let mut diagnostics = vec![];
+
+	for (file, text) in &files {
+		if let Err(err) = toml::from_str::<toml::Table>(text) {
+			let offset = err.span().map(|span| span.start).unwrap_or(0);
+			diagnostics.push(diagnostic(file, text, offset, err.message().into()));
+		}
+	}
+
+	if ! diagnostics.is_empty() {
+		return diagnostics;
+	}
+
+	let Ok((table, sources)) = service::merged(service_name) else {
+		return diagnostics;
+	};
+
+	// The file a key comes from, or the base file for keys missing altogether.
+	let source = |path: &str| {
+		let found = sources.iter().find(|(key, _)| same_key(key, path))
+			.or_else(|| sources.iter().find(|(key, _)| same_key(key.split('.').next().unwrap(), path)));
+		found.map(|(_, file)| file.as_str()).unwrap_or(&files[0].0)
+	};
+
+	let service = if files.len() == 1 {
+		let (file, text) = &files[0];
+		toml::from_str::<service::Service>(text).map_err(|err| {
+			let offset = err.span().map(|span| span.start).unwrap_or(0);
+			diagnostic(file, text, offset, suggest(err.message()))
+		})
+	} else {
+		// Errors are located in the merged file, then in the file the key comes from.
+		let merged = toml::to_string(&table).unwrap_or_default();
+		toml::from_str::<service::Service>(&merged).map_err(|err| {
+			let offset = err.span().map(|span| span.start).unwrap_or(0);
+			let path = path_at(&merged, offset);
+			let file = source(&path);
+			let text = text_of(file);
+			diagnostic(file, text, locate(text, &path), suggest(err.message()))
+		})
+	};
+
+	match service {
+		Ok(service) => {
+			let mut problems = service.problems();
+
+			// Only the first of two conflicting services is started at boot,
+			// whichever of the two lists the other.
+			if service::enabled(service_name) {
+				let conflicts = service::list().into_keys()
+					.filter(|other| other != service_name && service::enabled(other))
+					.filter(|other| service::conflicts(service_name, &service, other));
+				for conflict in conflicts {
+					problems.push(("control.conflicts", format!("service `{conflict}` is enabled as well")));
+				}
+			}
+
+			for (path, message) in problems {
+				let file = source(path);
+				let text = text_of(file);
+				diagnostics.push(diagnostic(file, text, locate(text, path), message));
+			}
+		},
+		Err(diagnostic) => diagnostics.push(diagnostic),
+	}
+
+	diagnostics
+}
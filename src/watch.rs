@@ -0,0 +1,208 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::{command, service};
+use std::collections::BTreeSet;
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Watch {
+	#[serde(alias="path-exists", default="service::xvec")]
+	path_exists: Vec<String>,
+	#[serde(alias="path-changed", default="service::xvec")]
+	path_changed: Vec<String>,
+	#[serde(alias="directory-not-empty", default="service::xvec")]
+	directory_not_empty: Vec<String>,
+}
+
+/// Events of the watched paths and of the directories they are created in.
+const EVENTS: u32 = libc::IN_CLOSE_WRITE | libc::IN_ATTRIB | libc::IN_CREATE | libc::IN_DELETE
+	| libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_DELETE_SELF | libc::IN_MOVE_SELF;
+
+impl Watch {
+	fn paths(&self) -> impl Iterator<Item = (&'static str, &String)> {
+		let exists = self.path_exists.iter().map(|path| ("path.path-exists", path));
+		let changed = self.path_changed.iter().map(|path| ("path.path-changed", path));
+		let not_empty = self.directory_not_empty.iter().map(|path| ("path.directory-not-empty", path));
+		exists.chain(changed).chain(not_empty)
+	}
+
+	/// Details shown by `control status --verbose`.
+	pub fn properties(&self) -> Vec<(&'static str, String)> {
+		let mut properties = vec![];
+
+		for (key, paths) in [
+			("Path exists", &self.path_exists),
+			("Path changed", &self.path_changed),
+			("Directory not empty", &self.directory_not_empty),
+		] {
+			if ! paths.is_empty() {
+				properties.push((key, paths.join(", ")));
+			}
+		}
+
+		properties
+	}
+
+	/// Problems that would prevent the paths from being watched.
+	pub fn problems(&self) -> Vec<(&'static str, String)> {
+		let mut problems = vec![];
+
+		if self.paths().next().is_none() {
+			problems.push(("path", "path without path-exists, path-changed or directory-not-empty".into()));
+		}
+
+		for (key, path) in self.paths() {
+			if ! path.starts_with('/') {
+				problems.push((key, format!("path `{path}` is not absolute")));
+			}
+		}
+
+		problems
+	}
+
+	/// Whether a path exists or a directory has entries.
+	fn holds(&self) -> bool {
+		let not_empty = |dir: &String| fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some());
+		self.path_exists.iter().any(|path| Path::new(path).exists())
+			|| self.directory_not_empty.iter().any(not_empty)
+	}
+}
+
+/// Watch the path, or the closest directory it would be created in.
+fn add_watch(inotify: i32, path: &str) -> Option<(i32, bool)> {
+	let mut watched = Path::new(path);
+
+	loop {
+		let cpath = CString::new(watched.as_os_str().as_bytes()).ok()?;
+		let wd = unsafe { libc::inotify_add_watch(inotify, cpath.as_ptr(), EVENTS) };
+		if wd >= 0 {
+			return Some((wd, watched == Path::new(path)));
+		}
+		watched = watched.parent()?;
+	}
+}
+
+/// Wait for events of the inotify instance, the changed paths are returned.
+///
+/// Events following each other closely, like creating then writing a file,
+/// are gathered as a single change, for at most 100ms after the first one.
+fn wait(inotify: i32, changed: &[(String, Option<(i32, bool)>)]) -> Vec<bool> {
+	const GATHER: Duration = Duration::from_millis(100);
+
+	let mut flags = vec![false; changed.len()];
+	let mut pollfd = libc::pollfd { fd: inotify, events: libc::POLLIN, revents: 0 };
+	let mut timeout = 1000;
+	let mut first: Option<Instant> = None;
+
+	while unsafe { libc::poll(&mut pollfd, 1, timeout) } > 0 {
+		let gathered = first.get_or_insert_with(Instant::now).elapsed();
+		timeout = GATHER.saturating_sub(gathered).as_millis() as i32;
+
+		let mut buffer = [0u8; 4096];
+		let len = unsafe { libc::read(inotify, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+		let mut offset = 0;
+		let header = std::mem::size_of::<libc::inotify_event>();
+
+		while len > 0 && offset + header <= len as usize {
+			let event = unsafe { std::ptr::read_unaligned(buffer[offset ..].as_ptr() as *const libc::inotify_event) };
+			let name = &buffer[offset + header .. offset + header + event.len as usize];
+			let name = OsStr::from_bytes(name.split(|byte| *byte == 0).next().unwrap_or(&[]));
+			offset += header + event.len as usize;
+
+			// Events of the path itself, or of its entry in the watched directory.
+			for (i, (path, watch)) in changed.iter().enumerate() {
+				match watch {
+					Some((wd, true)) if *wd == event.wd => flags[i] = true,
+					Some((wd, false)) if *wd == event.wd => {
+						flags[i] |= Path::new(path).file_name() == Some(name);
+					},
+					_ => {},
+				}
+			}
+		}
+
+		if timeout == 0 {
+			break;
+		}
+	}
+
+	flags
+}
+
+/// Start the service when one of its paths exists, changes or gets entries.
+///
+/// A path changing while the service runs starts it again once it exited,
+/// services are started at most once per second.
+pub fn watch(service_name: &str, children: &Arc<Mutex<command::Children>>) -> io::Result<()> {
+	let service_name = String::from(service_name);
+	let children = Arc::clone(children);
+
+	let Some(watch) = service::load(&service_name).ok().and_then(|service| service.path) else {
+		return Err(io::ErrorKind::NotFound.into());
+	};
+
+	let inotify = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+	if inotify < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	thread::spawn(move || {
+		let mut run: Option<thread::JoinHandle<()>> = None;
+		let mut started: Option<Instant> = None;
+		let mut pending = false;
+		let mut existed: Vec<bool> = watch.path_changed.iter().map(|path| Path::new(path).exists()).collect();
+		let mut watched: BTreeSet<i32> = BTreeSet::new();
+
+		loop {
+			// Paths are watched again, they might have been created since,
+			// and the directories they were awaited in are not watched anymore.
+			let wds: BTreeSet<i32> = watch.paths().filter_map(|(_, path)| add_watch(inotify, path))
+				.map(|(wd, _)| wd).collect();
+			for wd in watched.difference(&wds) {
+				unsafe { libc::inotify_rm_watch(inotify, *wd) };
+			}
+			watched = wds;
+
+			let changed: Vec<(String, Option<(i32, bool)>)> = watch.path_changed.iter()
+				.map(|path| (path.clone(), add_watch(inotify, path)))
+				.collect();
+
+			let running = run.as_ref().is_some_and(|run| ! run.is_finished())
+				|| service::pidfile_get(&service_name).is_some();
+			let recent = started.is_some_and(|started| started.elapsed() < Duration::from_secs(1));
+
+			if (pending || watch.holds()) && ! running && ! recent {
+				pending = false;
+				started = Some(Instant::now());
+
+				let service_name = service_name.clone();
+				let children = Arc::clone(&children);
+				run = Some(thread::spawn(move || {
+					let _ = service::start(&service_name, &children);
+				}));
+			}
+
+			let flags = wait(inotify, &changed);
+
+			// Paths created or removed between two waits count as changed.
+			for (i, (path, _)) in changed.iter().enumerate() {
+				let exists = Path::new(path).exists();
+				pending |= flags[i] || exists != existed[i];
+				existed[i] = exists;
+			}
+		}
+	});
+
+	Ok(())
+}